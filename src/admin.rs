@@ -0,0 +1,187 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, http::header};
+use futures::{stream, StreamExt};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::cache::{key_host, IconCache};
+use crate::config::{AdminConfig, Config};
+use crate::error::GetIconError;
+use crate::handlers::prefetch_icon;
+use crate::models::{BatchRequest, PurgeRequest};
+use crate::url_utils::normalize_url;
+use log::{debug, info, warn};
+
+/// Bearer token required by the /admin and /debug routes, registered as app data
+/// Without it those routes answer 404, as if they didn't exist
+#[derive(Clone)]
+pub struct AdminToken(pub String);
+
+impl AdminToken {
+    /// The token configured in `admin.token`, if set
+    pub fn from_config(config: &AdminConfig) -> Option<Self> {
+        let token = config.token.as_deref().map(str::trim).filter(|t| !t.is_empty())?;
+        info!("Admin endpoints enabled");
+        Some(AdminToken(token.to_string()))
+    }
+}
+
+/// Checks the request's bearer token, returning the response to send if it isn't allowed
+pub(crate) fn reject_unauthorized(req: &HttpRequest, token: Option<&web::Data<AdminToken>>) -> Option<HttpResponse> {
+    let Some(token) = token else {
+        return Some(HttpResponse::NotFound().finish());
+    };
+    let provided = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    match provided {
+        Some(provided) if constant_time_eq(provided.as_bytes(), token.0.as_bytes()) => None,
+        _ => {
+            warn!("Rejected unauthorized admin request to {}", req.path());
+            Some(HttpResponse::Unauthorized()
+                .append_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .body("Missing or invalid admin token"))
+        }
+    }
+}
+
+/// Compares two byte strings in time independent of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Handler for /admin/cache - shows the cached entry and metadata for a cache key
+#[get("/admin/cache")]
+pub async fn admin_cache_entry(
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+    token: Option<web::Data<AdminToken>>,
+    cache: web::Data<Arc<IconCache>>
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, token.as_ref()) {
+        return response;
+    }
+    let Some(key) = query.get("key") else {
+        return GetIconError::InvalidRequest("Missing key parameter".to_string()).error_response(None);
+    };
+
+    let entry = cache.peek(key).await;
+    let failure = cache.last_failure(key).await;
+    let negative = failure.as_ref().is_some_and(|failure| failure.is_active());
+    let on_disk = cache.on_disk(key);
+    if entry.is_none() && failure.is_none() && on_disk != Some(true) {
+        return HttpResponse::NotFound().json(json!({ "key": key, "error": "Key is not cached" }));
+    }
+
+    let entry = entry.map(|(entry, needs_refresh)| json!({
+        "state": if needs_refresh { "stale" } else { "fresh" },
+        "content_type": entry.content_type,
+        "etag": entry.etag,
+        "bytes": entry.content.len(),
+        "age_seconds": entry.inserted_at.elapsed().as_secs(),
+        "access_count": entry.access_count,
+        "upstream": entry.upstream,
+    }));
+    let failure = failure.map(|failure| json!({
+        "kind": failure.kind,
+        "attempts": failure.attempts,
+        "age_seconds": failure.failed_at.elapsed().as_secs(),
+        "retry_in_seconds": failure.remaining().as_secs(),
+    }));
+    HttpResponse::Ok().json(json!({
+        "key": key,
+        "entry": entry,
+        "negative": negative,
+        "failure": failure,
+        "disk": on_disk,
+    }))
+}
+
+/// Handler for /admin/purge - removes a site, or every site under a domain prefix, from all cache tiers
+#[post("/admin/purge")]
+pub async fn admin_purge(
+    req: HttpRequest,
+    body: web::Json<PurgeRequest>,
+    token: Option<web::Data<AdminToken>>,
+    cache: web::Data<Arc<IconCache>>
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, token.as_ref()) {
+        return response;
+    }
+
+    let (removed, sites, disk) = match (&body.site, &body.prefix) {
+        (Some(site), None) => {
+            let Some(host) = normalize_url(site).await.and_then(|u| u.host_str().map(str::to_string)) else {
+                return GetIconError::InvalidRequest("Invalid site".to_string()).error_response(None);
+            };
+            info!("Purging cache for site: {}", host);
+            cache.purge(|key| key_host(key) == Some(host.as_str())).await
+        },
+        (None, Some(prefix)) if !prefix.trim().is_empty() => {
+            let prefix = prefix.trim().to_ascii_lowercase();
+            info!("Purging cache for domains starting with: {}", prefix);
+            cache.purge(|key| key_host(key).is_some_and(|host| host.starts_with(prefix.as_str()))).await
+        },
+        _ => return GetIconError::InvalidRequest("Expected either a site or a non-empty prefix".to_string()).error_response(None),
+    };
+
+    let mut response = json!({
+        "removed": { "cache": removed, "discovery": sites, "disk": disk }
+    });
+    // Discovered icon lists, in-flight fetches and the disk tier belong to each process
+    if cache.backend_name() != "memory" {
+        response["note"] = json!(
+            "Cached icons were purged for all replicas, but discovered icon lists and disk entries only on this one; \
+             other replicas keep serving their icon lists until they expire (cache.ttl_seconds)"
+        );
+    }
+    HttpResponse::Ok().json(response)
+}
+
+/// Handler for /admin/prewarm - fetches icons for a list of sites in the background
+#[post("/admin/prewarm")]
+pub async fn admin_prewarm(
+    req: HttpRequest,
+    body: web::Json<BatchRequest>,
+    token: Option<web::Data<AdminToken>>,
+    client: web::Data<reqwest::Client>,
+    cache: web::Data<Arc<IconCache>>,
+    config: web::Data<Config>
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, token.as_ref()) {
+        return response;
+    }
+    let batch = body.into_inner();
+    if batch.urls.len() > config.batch.max_urls {
+        return GetIconError::InvalidRequest(format!("Too many URLs, at most {} are allowed per request", config.batch.max_urls)).error_response(None);
+    }
+    let concurrency = config.batch.concurrency;
+
+    let queued = batch.urls.len();
+    info!("Pre-warming cache for {} sites", queued);
+    actix_web::rt::spawn(async move {
+        let warmed = stream::iter(batch.urls)
+            .map(|url_str| {
+                let client = client.clone();
+                let cache = cache.clone();
+                let config = config.clone();
+                async move {
+                    match prefetch_icon(client.as_ref(), cache.as_ref(), &config.discovery, &url_str, batch.size).await {
+                        Ok(()) => true,
+                        Err(err) => {
+                            debug!("Pre-warming {} failed ({}): {}", url_str, err.code(), err);
+                            false
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(concurrency)
+            .filter(|warmed| futures::future::ready(*warmed))
+            .count()
+            .await;
+        info!("Pre-warmed {} of {} sites", warmed, queued);
+    });
+
+    HttpResponse::Accepted().json(json!({ "queued": queued }))
+}
@@ -0,0 +1,373 @@
+use env_logger::Env;
+use futures::{stream, StreamExt};
+use geticon::config::Config;
+use geticon::error::GetIconError;
+use geticon::fetcher::{Discovery, FetchedIcon, IconFetcher};
+use geticon::models::IconResponse;
+use geticon::security::set_private_addresses_allowed;
+use geticon::transform::{ImageOptions, OutputFormat, MAX_RESIZE_DIMENSION};
+use pico_args::Arguments;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Fetch and inspect website icons without running the server
+
+Usage:
+  geticon-cli discover <url> [--json] [--size N]
+  geticon-cli fetch <url> [--size N] [--resize] [--format png|webp|jpeg|ico] [-o FILE]
+  geticon-cli batch <file> [--out-dir DIR] [--size N] [--concurrency N]
+
+Commands:
+  discover   List the validated icons of a site, best first (* marks the one fetch picks)
+  fetch      Download the best icon; the format follows --format or the -o extension,
+             and without -o it is saved as <host>.<ext>
+  batch      Download the best icon of every site listed in a file, one per line
+             (blank lines and lines starting with # are skipped)
+
+Options:
+  --json               Print the same document as the /json endpoint
+  --size N             Pick the icon closest to N pixels
+  --resize             Resample to exactly N x N pixels
+  --format F           Convert to png, webp, jpeg or ico
+  -o, --output FILE    Where to save the icon
+  --out-dir DIR        Directory for batch downloads [default: icons]
+  --concurrency N      Sites fetched at once in batch mode [default: 8]
+  --timeout SECS       Timeout of each request [default: 10]
+  -h, --help           Print this help
+  -V, --version        Print the version
+
+Logging is controlled with RUST_LOG, e.g. RUST_LOG=debug.";
+
+/// Why the command failed: bad arguments, or a failure while running it
+enum CliError {
+    Usage(String),
+    Failed(String),
+}
+
+impl From<pico_args::Error> for CliError {
+    fn from(err: pico_args::Error) -> Self {
+        CliError::Usage(err.to_string())
+    }
+}
+
+impl From<GetIconError> for CliError {
+    fn from(err: GetIconError) -> Self {
+        CliError::Failed(format!("{}: {}", err.code(), err))
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init_from_env(Env::default().default_filter_or("error"));
+
+    // Same address guard settings as the server, from geticon.toml or GETICON_ALLOW_PRIVATE_ADDRESSES
+    match Config::load() {
+        Ok(config) => set_private_addresses_allowed(config.security.allow_private_addresses),
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    match run(Arguments::from_env()).await {
+        Ok(code) => code,
+        Err(CliError::Usage(message)) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            ExitCode::from(2)
+        },
+        Err(CliError::Failed(message)) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+async fn run(mut args: Arguments) -> Result<ExitCode, CliError> {
+    if args.contains(["-h", "--help"]) {
+        println!("{}", USAGE);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.contains(["-V", "--version"]) {
+        println!("geticon-cli {}", env!("CARGO_PKG_VERSION"));
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let timeout: u64 = args.opt_value_from_str("--timeout")?.unwrap_or(10);
+    let size: Option<u32> = args.opt_value_from_str("--size")?;
+    let fetcher = IconFetcher::builder()
+        .with_timeout(Duration::from_secs(timeout))
+        .build()
+        .map_err(|err| CliError::Failed(format!("failed to build HTTP client: {}", err)))?;
+
+    match args.subcommand()?.as_deref() {
+        Some("discover") => {
+            let json = args.contains("--json");
+            let url: String = args.free_from_str()?;
+            finish(args)?;
+            discover(&fetcher, &url, size, json).await
+        },
+        Some("fetch") => {
+            let resize = args.contains("--resize");
+            let format = args.opt_value_from_fn("--format", parse_format)?;
+            let output: Option<PathBuf> = args.opt_value_from_str(["-o", "--output"])?;
+            let url: String = args.free_from_str()?;
+            finish(args)?;
+            if resize && !size.is_some_and(|size| size > 0 && size <= MAX_RESIZE_DIMENSION) {
+                return Err(CliError::Usage(format!("--resize needs a --size between 1 and {}", MAX_RESIZE_DIMENSION)));
+            }
+            // Without --format, the extension of the output file decides
+            let format = format.or_else(|| {
+                output.as_ref().and_then(|path| path.extension()).and_then(|ext| OutputFormat::from_param(&ext.to_string_lossy()))
+            });
+            let options = ImageOptions::new(size, resize).with_format(format);
+            fetch(&fetcher, &url, &options, output).await
+        },
+        Some("batch") => {
+            let out_dir: PathBuf = args.opt_value_from_str("--out-dir")?.unwrap_or_else(|| PathBuf::from("icons"));
+            let concurrency: usize = args.opt_value_from_str("--concurrency")?.unwrap_or(8);
+            let file: PathBuf = args.free_from_str()?;
+            finish(args)?;
+            batch(&fetcher, &file, &out_dir, size, concurrency).await
+        },
+        Some(command) => Err(CliError::Usage(format!("unknown command: {}", command))),
+        None => Err(CliError::Usage("missing command".to_string())),
+    }
+}
+
+/// Rejects arguments left over after parsing
+fn finish(args: Arguments) -> Result<(), CliError> {
+    let remaining = args.finish();
+    if remaining.is_empty() {
+        Ok(())
+    } else {
+        Err(CliError::Usage(format!("unexpected arguments: {:?}", remaining)))
+    }
+}
+
+fn parse_format(value: &str) -> Result<OutputFormat, String> {
+    OutputFormat::from_param(value).ok_or_else(|| "expected one of: png, webp, jpeg, ico".to_string())
+}
+
+async fn discover(fetcher: &IconFetcher, url: &str, size: Option<u32>, json: bool) -> Result<ExitCode, CliError> {
+    let discovery = fetcher.discover(url).await?;
+    if json {
+        let response = IconResponse {
+            url: discovery.url.host_str().unwrap_or_default().to_string(),
+            best_icon: discovery.best(size).cloned(),
+            icons: discovery.icons,
+        };
+        let text = serde_json::to_string_pretty(&response)
+            .map_err(|err| CliError::Failed(format!("failed to serialize icons: {}", err)))?;
+        print_out(&format!("{}\n", text));
+    } else {
+        print_out(&candidate_table(&discovery, size));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Formats the icons of a site as an aligned table
+fn candidate_table(discovery: &Discovery, size: Option<u32>) -> String {
+    let best = discovery.best(size);
+    let mut rows = vec![["", "SCORE", "SIZE", "TYPE", "SOURCE", "URL"].map(String::from)];
+    for icon in &discovery.icons {
+        let dimensions = match (icon.width, icon.height) {
+            (Some(width), Some(height)) => format!("{}x{}", width, height),
+            _ => "?".to_string(),
+        };
+        let url = match icon.frame {
+            Some(frame) => format!("{} (frame {})", icon.url, frame),
+            None => icon.url.clone(),
+        };
+        let marker = if best == Some(icon) { "*" } else { "" };
+        rows.push([
+            marker.to_string(),
+            icon.score.to_string(),
+            dimensions,
+            icon.content_type.clone(),
+            icon.source.map_or("-", |source| source.as_str()).to_string(),
+            url,
+        ]);
+    }
+
+    let mut widths = [0; 6];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    for row in &rows {
+        let cells: Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+async fn fetch(fetcher: &IconFetcher, url: &str, options: &ImageOptions, output: Option<PathBuf>) -> Result<ExitCode, CliError> {
+    let fetched = fetcher.fetch(url, options).await?;
+    let path = output.unwrap_or_else(|| PathBuf::from(file_name(&fetched)));
+    save(&fetched, &path).await?;
+    eprintln!("Saved {} ({}) to {}", fetched.icon.url, fetched.content_type, path.display());
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Fetches the icon of every site in `file` into `out_dir`; fails if any site failed
+async fn batch(fetcher: &IconFetcher, file: &Path, out_dir: &Path, size: Option<u32>, concurrency: usize) -> Result<ExitCode, CliError> {
+    let text = tokio::fs::read_to_string(file)
+        .await
+        .map_err(|err| CliError::Failed(format!("failed to read {}: {}", file.display(), err)))?;
+    let sites: Vec<&str> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    tokio::fs::create_dir_all(out_dir)
+        .await
+        .map_err(|err| CliError::Failed(format!("failed to create {}: {}", out_dir.display(), err)))?;
+
+    let results: Vec<bool> = stream::iter(&sites)
+        .map(|site| async move {
+            let result = match fetcher.fetch_best(site, size).await {
+                Ok(fetched) => {
+                    let path = out_dir.join(file_name(&fetched));
+                    save(&fetched, &path).await.map(|()| path)
+                },
+                Err(err) => Err(err.into()),
+            };
+            match result {
+                Ok(path) => {
+                    print_out(&format!("ok     {} -> {}\n", site, path.display()));
+                    true
+                },
+                Err(CliError::Failed(message) | CliError::Usage(message)) => {
+                    print_out(&format!("failed {}: {}\n", site, message));
+                    false
+                },
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+
+    let saved = results.iter().filter(|ok| **ok).count();
+    eprintln!("Saved {} of {} icons to {}", saved, sites.len(), out_dir.display());
+    Ok(if saved == sites.len() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
+
+/// Writes to stdout, ignoring a closed pipe (e.g. when piped to `head`)
+fn print_out(text: &str) {
+    let _ = io::stdout().lock().write_all(text.as_bytes());
+}
+
+/// `<host>.<ext>` for a fetched icon, with the extension matching its content type
+fn file_name(fetched: &FetchedIcon) -> String {
+    let extension = match fetched.content_type.as_str() {
+        "image/svg+xml" => "svg",
+        "image/gif" => "gif",
+        content_type => OutputFormat::from_content_type(content_type).map_or("img", |format| match format {
+            OutputFormat::Jpeg => "jpg",
+            format => format.as_str(),
+        }),
+    };
+    format!("{}.{}", fetched.url.host_str().unwrap_or("icon"), extension)
+}
+
+async fn save(fetched: &FetchedIcon, path: &Path) -> Result<(), CliError> {
+    tokio::fs::write(path, &fetched.content)
+        .await
+        .map_err(|err| CliError::Failed(format!("failed to write {}: {}", path.display(), err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use geticon::models::{Icon, IconSource};
+    use std::ffi::OsString;
+    use url::Url;
+
+    fn args(values: &[&str]) -> Arguments {
+        Arguments::from_vec(values.iter().map(OsString::from).collect())
+    }
+
+    fn icon(url: &str, content_type: &str, size: Option<u32>, score: u32, source: Option<IconSource>) -> Icon {
+        let mut icon = Icon::new(url.to_string(), content_type.to_string(), size, size);
+        icon.score = score;
+        icon.source = source;
+        icon
+    }
+
+    #[tokio::test]
+    async fn test_argument_errors() {
+        // Every case fails while parsing, before anything is fetched
+        let cases: [(&[&str], &str); 6] = [
+            (&["fetch", "example.com", "--resize"], "--resize needs a --size"),
+            (&["fetch", "example.com", "--resize", "--size", "0"], "--resize needs a --size"),
+            (&["fetch", "example.com", "--format", "bmp"], "expected one of: png, webp, jpeg, ico"),
+            (&["lookup", "example.com"], "unknown command: lookup"),
+            (&[], "missing command"),
+            (&["discover", "example.com", "extra"], "unexpected arguments"),
+        ];
+        for (values, expected) in cases {
+            match run(args(values)).await {
+                Err(CliError::Usage(message)) => assert!(message.contains(expected), "{:?}: {}", values, message),
+                _ => panic!("{:?} should be a usage error", values),
+            }
+        }
+    }
+
+    #[test]
+    fn test_file_name() {
+        let url = Url::parse("https://example.com/").unwrap();
+        let fetched = |content_type: &str| FetchedIcon {
+            url: url.clone(),
+            icon: icon("https://example.com/icon", content_type, None, 0, None),
+            content: Bytes::new(),
+            content_type: content_type.to_string(),
+        };
+        let cases = [
+            ("image/png", "example.com.png"),
+            ("image/webp", "example.com.webp"),
+            ("image/jpeg", "example.com.jpg"),
+            ("image/vnd.microsoft.icon", "example.com.ico"),
+            ("image/svg+xml", "example.com.svg"),
+            ("image/gif", "example.com.gif"),
+            ("application/octet-stream", "example.com.img"),
+        ];
+        for (content_type, expected) in cases {
+            assert_eq!(file_name(&fetched(content_type)), expected);
+        }
+    }
+
+    #[test]
+    fn test_candidate_table() {
+        let mut frame = icon("https://example.com/favicon.ico", "image/x-icon", Some(32), 40, Some(IconSource::WellKnown));
+        frame.frame = Some(1);
+        let discovery = Discovery {
+            url: Url::parse("https://example.com/").unwrap(),
+            icons: vec![
+                icon("https://example.com/apple-touch-icon.png", "image/png", Some(180), 100, Some(IconSource::Html)),
+                frame,
+                icon("https://example.com/logo.svg", "image/svg+xml", None, 10, None),
+            ],
+        };
+
+        // Columns are aligned, trailing spaces trimmed and the best icon marked
+        assert_eq!(candidate_table(&discovery, None), concat!(
+            "   SCORE  SIZE     TYPE           SOURCE      URL\n",
+            "*  100    180x180  image/png      html        https://example.com/apple-touch-icon.png\n",
+            "   40     32x32    image/x-icon   well_known  https://example.com/favicon.ico (frame 1)\n",
+            "   10     ?        image/svg+xml  -           https://example.com/logo.svg\n",
+        ));
+
+        // The marker follows the requested size
+        let table = candidate_table(&discovery, Some(32));
+        let marked: Vec<&str> = table.lines().filter(|line| line.starts_with('*')).collect();
+        assert_eq!(marked.len(), 1);
+        assert!(marked[0].ends_with("(frame 1)"));
+    }
+}
@@ -1,16 +1,32 @@
+use futures::future::BoxFuture;
+use futures::FutureExt;
 use moka::future::Cache;
+use moka::notification::RemovalCause;
+use std::future::Future;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::time::Instant;
 use bytes::Bytes;
+use reqwest::header::{HeaderMap, HeaderName, ETAG, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use crate::models::Icon;
+use crate::config::CacheConfig;
+use crate::disk_cache::{DiskCache, DiskEntry};
+use crate::failure::{FailureKind, NegativeEntry, NEGATIVE_HISTORY_SECONDS};
+use crate::metrics::{metrics, CacheLookup};
 use log::{info, debug};
 
-/// Cache for storing fetched icons to avoid repeated requests
-/// Enhanced with dual-layer caching system for handling expired entries
-pub struct IconCache {
-    main_cache: Cache<String, Arc<CacheEntry>>,     // Primary cache with normal TTL
-    expired_cache: Cache<String, Arc<CacheEntry>>,  // Secondary cache for expired entries
-    negative_cache: Cache<String, ()>,              // For URLs that failed validation
-}
+/// How long entries stay in the stale layer after leaving the main cache (3 days)
+pub const STALE_TTL_SECONDS: u64 = 259200;
+
+/// Number of entries kept by caches bounded by entry count, and by the discovery cache
+pub const DEFAULT_MAX_CAPACITY: u64 = 2000;
+
+/// Byte budget of each layer of the default in-memory cache (64 MB)
+pub const DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// How long entries are served as fresh by default (2 hours)
+pub const DEFAULT_TTL_SECONDS: u64 = 7200;
 
 /// Entry in the icon cache
 #[derive(Clone)]
@@ -19,56 +35,225 @@ pub struct CacheEntry {
     pub content_type: String,
     pub etag: String,
     pub access_count: u32, // Track how often this entry is accessed
+    pub inserted_at: Instant, // When the content was fetched, used to decide freshness
+    pub upstream: Option<UpstreamValidators>, // Origin validators, used to revalidate instead of downloading again
 }
 
-impl IconCache {
-    /// Create a new icon cache with the specified max capacity and TTL
+/// How the origin identified the icon it served, so a refresh can send a conditional request
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpstreamValidators {
+    pub url: String, // Icon URL the content was downloaded from
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl UpstreamValidators {
+    /// Reads the ETag and Last-Modified headers of an origin response
+    /// Returns None when the origin sent neither, since the icon can't be revalidated then
+    pub fn from_headers(url: &str, headers: &HeaderMap) -> Option<Self> {
+        let value = |name: HeaderName| headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let etag = value(ETAG);
+        let last_modified = value(LAST_MODIFIED);
+        if etag.is_none() && last_modified.is_none() {
+            return None;
+        }
+        Some(UpstreamValidators { url: url.to_string(), etag, last_modified })
+    }
+}
+
+impl CacheEntry {
+    /// Creates an entry for freshly fetched content
+    pub fn new(content: Bytes, content_type: String, etag: String) -> Self {
+        CacheEntry {
+            content,
+            content_type,
+            etag,
+            access_count: 1,
+            inserted_at: Instant::now(),
+            upstream: None,
+        }
+    }
+    
+    /// Returns a fresh copy of this entry, used when the origin confirmed it is unchanged (304)
+    /// The content and our own ETag stay the same
+    pub fn revalidated(&self) -> Self {
+        CacheEntry {
+            access_count: 1,
+            inserted_at: Instant::now(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Storage for cached responses and failed lookups
+/// The default is the in-process `MemoryBackend`; other backends let several replicas share one cache
+pub trait CacheBackend: Send + Sync {
+    /// Name of the backend, used as the cache tier label in metrics
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+    
+    /// Returns (CacheEntry, needs_refresh), where needs_refresh means the entry is past its TTL
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<(Arc<CacheEntry>, bool)>>;
+    
+    /// Stores an entry; its `inserted_at` decides when it becomes stale
+    fn insert(&self, key: String, entry: Arc<CacheEntry>) -> BoxFuture<'_, ()>;
+    
+    /// Drops the stale copy of an entry after it was refreshed
+    fn remove_stale<'a>(&'a self, _key: &'a str) -> BoxFuture<'a, ()> {
+        async {}.boxed()
+    }
+    
+    /// Remembers why a key's lookup failed, to avoid repeated validation attempts
+    fn insert_negative(&self, key: String, entry: NegativeEntry) -> BoxFuture<'_, ()>;
+    
+    /// Returns the last remembered failure of a key, even once it is due for a retry
+    fn get_negative<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<NegativeEntry>>;
+    
+    /// Forgets a key's failures after a successful lookup
+    fn remove_negative<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()>;
+    
+    /// Returns the number of (fresh, stale, negative) entries
+    /// None if the backend can't count them cheaply; called on every /health and /metrics request
+    fn stats(&self) -> BoxFuture<'_, Option<(u64, u64, u64)>>;
+    
+    /// Returns an entry without counting it as an access or moving it between layers
+    fn peek<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<(Arc<CacheEntry>, bool)>> {
+        self.get(key)
+    }
+    
+    /// Removes every entry and negative entry whose key matches, returning how many were removed
+    fn purge<'a>(&'a self, matches: &'a (dyn Fn(&str) -> bool + Sync)) -> BoxFuture<'a, u64>;
+    
+    /// Periodic maintenance, called about once a minute
+    fn sweep(&self) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+    
+    /// Returns the configured and current byte usage, for backends bounded in bytes
+    fn byte_usage(&self) -> BoxFuture<'_, Option<ByteUsage>> {
+        async { None }.boxed()
+    }
+}
+
+/// In-process cache backend built on moka, with a main and an expired (stale) layer
+pub struct MemoryBackend {
+    main_cache: Cache<String, Arc<CacheEntry>>,     // Primary cache with normal TTL
+    expired_cache: Cache<String, Arc<CacheEntry>>,  // Secondary cache for expired entries
+    negative_cache: Cache<String, NegativeEntry>,    // For URLs that failed validation
+    ttl: Duration,                                  // How long an entry is served as fresh
+    stale_ttl: Duration,                            // How long an entry may be served stale after that
+    max_bytes: Option<u64>,                         // Byte budget of each layer, None when bounded by entry count
+}
+
+/// Byte usage of a cache whose layers are bounded in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteUsage {
+    pub max_bytes: u64, // Budget of each layer
+    pub main_bytes: u64,
+    pub expired_bytes: u64,
+}
+
+/// Weight of an entry in a byte-bounded cache: its content plus the strings stored with it
+fn entry_weight(key: &str, entry: &CacheEntry) -> u32 {
+    let bytes = key.len() + entry.content.len() + entry.content_type.len() + entry.etag.len();
+    bytes.try_into().unwrap_or(u32::MAX)
+}
+
+impl MemoryBackend {
+    /// Create a new memory backend with the specified max capacity (entries) and TTL
     pub fn new(max_capacity: u64, ttl_seconds: u64) -> Self {
-        let main_cache = Cache::builder()
+        Self::build(max_capacity, max_capacity / 2, None, ttl_seconds, STALE_TTL_SECONDS)
+    }
+    
+    /// Create a new memory backend whose main and expired layers each hold up to `max_bytes`
+    pub fn with_max_bytes(max_bytes: u64, ttl_seconds: u64) -> Self {
+        Self::build(max_bytes, DEFAULT_MAX_CAPACITY / 2, Some(max_bytes), ttl_seconds, STALE_TTL_SECONDS)
+    }
+    
+    /// Create a memory backend bounded in bytes with the configured limits and lifetimes
+    pub fn from_config(config: &CacheConfig) -> Self {
+        let max_bytes = config.max_bytes();
+        Self::build(max_bytes, config.max_capacity / 2, Some(max_bytes), config.ttl_seconds, config.stale_ttl_seconds)
+    }
+    
+    fn build(max_capacity: u64, negative_capacity: u64, max_bytes: Option<u64>, ttl_seconds: u64, stale_ttl_seconds: u64) -> Self {
+        // Expired cache has a longer TTL to serve as fallback while refreshing
+        let mut expired_builder = Cache::builder()
+            .max_capacity(max_capacity) // Same size as main cache
+            .time_to_live(Duration::from_secs(stale_ttl_seconds.max(1)));
+        if max_bytes.is_some() {
+            expired_builder = expired_builder.weigher(|key: &String, entry: &Arc<CacheEntry>| entry_weight(key, entry));
+        }
+        let expired_cache: Cache<String, Arc<CacheEntry>> = expired_builder.build();
+        
+        // Entries that expire or are evicted for space move to the expired cache
+        let stale_layer = expired_cache.clone();
+        let mut main_builder = Cache::builder()
             .max_capacity(max_capacity)
             .time_to_live(Duration::from_secs(ttl_seconds))
             .time_to_idle(Duration::from_secs(ttl_seconds * 2)) // Keep frequently accessed items longer
-            .build();
-        
-        // Expired cache has a longer TTL to serve as fallback while refreshing
-        let expired_cache = Cache::builder()
-            .max_capacity(max_capacity) // Same size as main cache
-            .time_to_live(Duration::from_secs(259200)) // 3 days (in seconds)
-            .build();
+            .async_eviction_listener(move |key: Arc<String>, entry: Arc<CacheEntry>, cause| {
+                let stale_layer = stale_layer.clone();
+                async move {
+                    if matches!(cause, RemovalCause::Expired | RemovalCause::Size) {
+                        debug!("Main cache entry {:?} ({:?}), moving to expired cache", key, cause);
+                        stale_layer.insert(key.to_string(), entry).await;
+                    }
+                }
+                .boxed()
+            });
+        if max_bytes.is_some() {
+            main_builder = main_builder.weigher(|key: &String, entry: &Arc<CacheEntry>| entry_weight(key, entry));
+        }
+        let main_cache = main_builder.build();
             
-        // Negative cache has shorter TTL to allow retrying failed URLs periodically
+        // Failures are kept past their retry time so repeated ones back off further;
+        // each entry decides itself when it is due for a retry
         let negative_cache = Cache::builder()
-            .max_capacity(max_capacity / 2) // Half the size of the main cache
-            .time_to_live(Duration::from_secs(ttl_seconds / 2)) // Half the TTL of the main cache 
+            .max_capacity(negative_capacity) // Half the size of the main cache
+            .time_to_live(Duration::from_secs(NEGATIVE_HISTORY_SECONDS))
             .build();
             
-        IconCache { 
+        MemoryBackend {
             main_cache,
             expired_cache,
-            negative_cache 
+            negative_cache,
+            ttl: Duration::from_secs(ttl_seconds),
+            stale_ttl: Duration::from_secs(stale_ttl_seconds),
+            max_bytes,
         }
     }
     
-    /// Get an entry from the cache
-    /// Returns (CacheEntry, needs_refresh)
-    /// If needs_refresh is true, the entry came from the expired cache and should be refreshed
-    pub async fn get(&self, key: &str) -> Option<(Arc<CacheEntry>, bool)> {
-        // First check if this key is in the negative cache
-        let in_negative = self.negative_cache.get(key).await.is_some();
-        if in_negative {
-            debug!("Cache hit (negative) for key: {}", key);
-            return None;
-        }
-        
-        // Then check the main cache
+    /// Move an entry from main cache to expired cache
+    /// Called when an entry in the main cache expires but we want to keep it for fallback
+    pub async fn move_to_expired(&self, key: String, entry: Arc<CacheEntry>) {
+        debug!("Moving to expired cache: {}", key);
+        self.expired_cache.insert(key, entry).await;
+    }
+    
+    async fn get_entry(&self, key: &str) -> Option<(Arc<CacheEntry>, bool)> {
+        // First check the main cache
         if let Some(entry) = self.main_cache.get(key).await {
+            // Entries past their TTL are moved to the expired cache and served stale
+            let age = entry.inserted_at.elapsed();
+            if age >= self.ttl + self.stale_ttl {
+                debug!("Main cache entry is too old to serve: {}", key);
+                self.main_cache.invalidate(key).await;
+                return None;
+            }
+            if age >= self.ttl {
+                debug!("Main cache entry is past its TTL: {}", key);
+                self.main_cache.invalidate(key).await;
+                self.move_to_expired(key.to_string(), entry.clone()).await;
+                return Some((entry, true)); // true = needs refresh
+            }
+            
             debug!("Main cache hit for key: {}", key);
             // We can't modify the Arc directly, so we'll create a new entry with incremented access_count
             let new_entry = Arc::new(CacheEntry {
-                content: entry.content.clone(),
-                content_type: entry.content_type.clone(),
-                etag: entry.etag.clone(),
                 access_count: entry.access_count + 1,
+                ..entry.as_ref().clone()
             });
             
             self.main_cache.insert(key.to_string(), new_entry.clone()).await;
@@ -77,76 +262,469 @@ impl IconCache {
             return Some((entry, false)); // false = doesn't need refresh
         }
         
-        // Finally check the expired cache
+        // Then check the expired cache
         if let Some(entry) = self.expired_cache.get(key).await {
+            if entry.inserted_at.elapsed() >= self.ttl + self.stale_ttl {
+                debug!("Expired cache entry is too old to serve: {}", key);
+                self.expired_cache.invalidate(key).await;
+                return None;
+            }
             debug!("Expired cache hit for key: {}", key);
             return Some((entry, true)); // true = needs refresh
         }
         
-        debug!("Cache miss for key: {}", key);
         None
     }
     
-    /// Insert an entry into the main cache
-    pub async fn insert(&self, key: String, content: Bytes, content_type: String, etag: String) {
+    /// Moves main cache entries that are past their TTL to the expired cache
+    /// Also runs moka's pending maintenance, which fires the eviction listener for expired entries
+    async fn move_expired_entries(&self) {
+        let expired: Vec<(Arc<String>, Arc<CacheEntry>)> = self.main_cache
+            .iter()
+            .filter(|(_, entry)| entry.inserted_at.elapsed() >= self.ttl)
+            .collect();
+        
+        for (key, entry) in expired {
+            self.main_cache.invalidate(key.as_str()).await;
+            self.move_to_expired(key.to_string(), entry).await;
+        }
+        
+        self.main_cache.run_pending_tasks().await;
+    }
+}
+
+impl CacheBackend for MemoryBackend {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<(Arc<CacheEntry>, bool)>> {
+        self.get_entry(key).boxed()
+    }
+    
+    fn insert(&self, key: String, entry: Arc<CacheEntry>) -> BoxFuture<'_, ()> {
+        async move {
+            // Entries that are already past their TTL (e.g. loaded from disk) go straight to the stale layer
+            if entry.inserted_at.elapsed() >= self.ttl {
+                self.move_to_expired(key, entry).await;
+            } else {
+                debug!("Inserting into main cache: {}", key);
+                self.main_cache.insert(key, entry).await;
+            }
+        }
+        .boxed()
+    }
+    
+    fn remove_stale<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()> {
+        async move {
+            debug!("Removing from expired cache: {}", key);
+            self.expired_cache.invalidate(key).await;
+        }
+        .boxed()
+    }
+    
+    fn insert_negative(&self, key: String, entry: NegativeEntry) -> BoxFuture<'_, ()> {
+        async move {
+            debug!("Inserting negative cache entry: {} ({:?}, attempt {})", key, entry.kind, entry.attempts);
+            self.negative_cache.insert(key, entry).await;
+        }
+        .boxed()
+    }
+    
+    fn get_negative<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<NegativeEntry>> {
+        async move { self.negative_cache.get(key).await }.boxed()
+    }
+    
+    fn remove_negative<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()> {
+        async move { self.negative_cache.invalidate(key).await }.boxed()
+    }
+    
+    fn stats(&self) -> BoxFuture<'_, Option<(u64, u64, u64)>> {
+        async move {
+            // Apply pending evictions first so the counts are current
+            self.main_cache.run_pending_tasks().await;
+            self.expired_cache.run_pending_tasks().await;
+            self.negative_cache.run_pending_tasks().await;
+            Some((
+                self.main_cache.entry_count(),
+                self.expired_cache.entry_count(),
+                self.negative_cache.entry_count(),
+            ))
+        }
+        .boxed()
+    }
+    
+    fn peek<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<(Arc<CacheEntry>, bool)>> {
+        async move {
+            if let Some(entry) = self.main_cache.get(key).await {
+                let needs_refresh = entry.inserted_at.elapsed() >= self.ttl;
+                return Some((entry, needs_refresh));
+            }
+            self.expired_cache.get(key).await.map(|entry| (entry, true))
+        }
+        .boxed()
+    }
+    
+    fn purge<'a>(&'a self, matches: &'a (dyn Fn(&str) -> bool + Sync)) -> BoxFuture<'a, u64> {
+        async move {
+            let mut removed = 0;
+            for cache in [&self.main_cache, &self.expired_cache] {
+                let keys: Vec<Arc<String>> = cache.iter().map(|(key, _)| key).filter(|key| matches(key)).collect();
+                for key in keys {
+                    cache.invalidate(key.as_str()).await;
+                    removed += 1;
+                }
+            }
+            let keys: Vec<Arc<String>> = self.negative_cache.iter().map(|(key, _)| key).filter(|key| matches(key)).collect();
+            for key in keys {
+                self.negative_cache.invalidate(key.as_str()).await;
+                removed += 1;
+            }
+            removed
+        }
+        .boxed()
+    }
+    
+    fn sweep(&self) -> BoxFuture<'_, ()> {
+        self.move_expired_entries().boxed()
+    }
+    
+    fn byte_usage(&self) -> BoxFuture<'_, Option<ByteUsage>> {
+        async move {
+            let max_bytes = self.max_bytes?;
+            self.main_cache.run_pending_tasks().await;
+            self.expired_cache.run_pending_tasks().await;
+            Some(ByteUsage {
+                max_bytes,
+                main_bytes: self.main_cache.weighted_size(),
+                expired_bytes: self.expired_cache.weighted_size(),
+            })
+        }
+        .boxed()
+    }
+}
+
+/// Returns the host a cache key belongs to
+/// Every key contains the normalized site URL, possibly behind a prefix such as `fallback:letter:`
+pub fn key_host(key: &str) -> Option<&str> {
+    let rest = &key[key.find("://")? + 3..];
+    let authority = rest.split('/').next()?;
+    let host = match authority.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => authority,
+    };
+    Some(host).filter(|host| !host.is_empty())
+}
+
+/// Cache for storing fetched icons to avoid repeated requests
+/// Entries live in a pluggable backend; discovery results, in-flight fetches and the
+/// optional disk tier are kept by this process
+pub struct IconCache {
+    backend: Arc<dyn CacheBackend>,                 // Where entries and failed lookups are stored
+    in_flight: Cache<String, Arc<CacheEntry>>,      // Fetches in progress, so concurrent misses share one
+    discovery_cache: Cache<String, Arc<Vec<Icon>>>, // Validated icons per site, shared by all sizes and formats
+    ttl: Duration,                                  // How long an entry is served as fresh
+    stale_ttl: Duration,                            // How long an entry may be served stale after that
+    disk: Option<Arc<DiskCache>>,                   // Optional persistent tier, checked after the backend
+}
+
+impl IconCache {
+    /// Create a new in-memory icon cache with the specified max capacity and TTL
+    pub fn new(max_capacity: u64, ttl_seconds: u64) -> Self {
+        Self::with_backend(Arc::new(MemoryBackend::new(max_capacity, ttl_seconds)), max_capacity, ttl_seconds)
+    }
+    
+    /// Create a new in-memory icon cache whose main and expired layers each hold up to `max_bytes`
+    pub fn with_max_bytes(max_bytes: u64, ttl_seconds: u64) -> Self {
+        Self::with_backend(Arc::new(MemoryBackend::with_max_bytes(max_bytes, ttl_seconds)), DEFAULT_MAX_CAPACITY, ttl_seconds)
+    }
+    
+    /// Create an in-memory icon cache bounded in bytes with the configured limits and lifetimes
+    pub fn from_config(config: &CacheConfig) -> Self {
+        let cache = Self::with_backend(Arc::new(MemoryBackend::from_config(config)), config.max_capacity, config.ttl_seconds)
+            .with_stale_ttl(config.stale_ttl_seconds);
+        info!("Created icon cache with dual-layer caching ({} MB per layer, {}s main TTL, {}s expired TTL)",
+            config.max_mb, config.ttl_seconds, config.stale_ttl_seconds);
+        cache
+    }
+    
+    /// Create an icon cache storing entries in the given backend
+    /// The capacity bounds the local discovery cache
+    pub fn with_backend(backend: Arc<dyn CacheBackend>, max_capacity: u64, ttl_seconds: u64) -> Self {
+        let in_flight = Cache::builder()
+            .max_capacity(max_capacity)
+            .build();
+            
+        // Discovery results live as long as the images built from them
+        let discovery_cache = Cache::builder()
+            .max_capacity(max_capacity)
+            .time_to_live(Duration::from_secs(ttl_seconds))
+            .build();
+            
+        IconCache { 
+            backend,
+            in_flight,
+            discovery_cache,
+            ttl: Duration::from_secs(ttl_seconds),
+            stale_ttl: Duration::from_secs(STALE_TTL_SECONDS),
+            disk: None,
+        }
+    }
+    
+    /// Sets how long entries may be served stale, matching the backend's stale TTL
+    pub fn with_stale_ttl(mut self, stale_ttl_seconds: u64) -> Self {
+        self.stale_ttl = Duration::from_secs(stale_ttl_seconds);
+        self
+    }
+    
+    /// Adds a persistent disk tier that is checked after the backend and before the network
+    pub fn with_disk_cache(mut self, disk: DiskCache) -> Self {
+        self.disk = Some(Arc::new(disk));
+        self
+    }
+    
+    /// Loads the newest entries from the disk tier into the backend, returning how many were loaded
+    /// Stops once the backend's byte budget is full; entries past their TTL are stored as stale so they are refreshed on first use
+    pub async fn warm_from_disk(&self) -> usize {
+        let disk = match &self.disk {
+            Some(disk) => disk.clone(),
+            None => return 0,
+        };
+        
+        // Fill the memory tier newest-first, reading one entry at a time, until its budget is used up
+        // Backends that aren't bounded in bytes are warmed with up to the default budget
+        let mut remaining = match self.backend.byte_usage().await {
+            Some(usage) => usage.max_bytes.saturating_sub(usage.main_bytes),
+            None => DEFAULT_MAX_BYTES,
+        };
+        let mut loaded = 0;
+        for key in disk.keys_newest_first() {
+            let Some(entry) = disk.get(&key).await else {
+                continue;
+            };
+            let weight = (entry.key.len() + entry.content.len() + entry.content_type.len() + entry.etag.len()) as u64;
+            if weight > remaining {
+                break;
+            }
+            if self.place_disk_entry(entry).await.is_some() {
+                remaining -= weight;
+                loaded += 1;
+            }
+        }
+        info!("Warmed icon cache with {} entries from disk", loaded);
+        loaded
+    }
+    
+    /// Puts an entry read from disk into the backend, keeping its original fetch time
+    /// Returns (CacheEntry, needs_refresh), or None if the entry is too old to serve
+    async fn place_disk_entry(&self, disk_entry: DiskEntry) -> Option<(Arc<CacheEntry>, bool)> {
+        if disk_entry.age >= self.ttl + self.stale_ttl {
+            if let Some(disk) = &self.disk {
+                disk.remove(&disk_entry.key).await;
+            }
+            return None;
+        }
+        
         let entry = Arc::new(CacheEntry {
-            content,
-            content_type,
-            etag,
+            content: disk_entry.content,
+            content_type: disk_entry.content_type,
+            etag: disk_entry.etag,
             access_count: 1,
+            inserted_at: Instant::now().checked_sub(disk_entry.age).unwrap_or_else(Instant::now),
+            upstream: disk_entry.upstream,
         });
         
-        debug!("Inserting into main cache: {}", key);
-        self.main_cache.insert(key, entry).await;
+        self.backend.insert(disk_entry.key, entry.clone()).await;
+        Some((entry, disk_entry.age >= self.ttl))
     }
     
-    /// Move an entry from main cache to expired cache
-    /// Called when an entry in the main cache expires but we want to keep it for fallback
-    pub async fn move_to_expired(&self, key: String, entry: Arc<CacheEntry>) {
-        debug!("Moving to expired cache: {}", key);
-        self.expired_cache.insert(key, entry).await;
+    /// Writes an entry to the disk tier, if one is configured
+    async fn persist(&self, key: &str, entry: &CacheEntry) {
+        if let Some(disk) = &self.disk {
+            disk.insert_entry(key, entry).await;
+        }
+    }
+    
+    /// Get an entry from the cache
+    /// Returns (CacheEntry, needs_refresh)
+    /// If needs_refresh is true, the entry is past its TTL and should be refreshed
+    pub async fn get(&self, key: &str) -> Option<(Arc<CacheEntry>, bool)> {
+        // First check if this key recently failed
+        if self.is_negative(key).await {
+            debug!("Cache hit (negative) for key: {}", key);
+            metrics().record_cache_lookup("negative", CacheLookup::Hit);
+            return None;
+        }
+        
+        if let Some(hit) = self.backend.get(key).await {
+            let result = if hit.1 { CacheLookup::StaleHit } else { CacheLookup::Hit };
+            metrics().record_cache_lookup(self.backend.name(), result);
+            return Some(hit);
+        }
+        metrics().record_cache_lookup(self.backend.name(), CacheLookup::Miss);
+        
+        // Then check the disk tier, promoting hits back into the backend
+        if let Some(disk) = &self.disk {
+            if let Some(disk_entry) = disk.get(key).await {
+                let hit = self.place_disk_entry(disk_entry).await;
+                let result = match &hit {
+                    Some((_, true)) => CacheLookup::StaleHit,
+                    Some((_, false)) => CacheLookup::Hit,
+                    None => CacheLookup::Miss,
+                };
+                metrics().record_cache_lookup("disk", result);
+                return hit;
+            }
+            metrics().record_cache_lookup("disk", CacheLookup::Miss);
+        }
+        
+        debug!("Cache miss for key: {}", key);
+        None
+    }
+    
+    /// Insert an entry into the cache
+    pub async fn insert(&self, key: String, content: Bytes, content_type: String, etag: String) {
+        let entry = Arc::new(CacheEntry::new(content, content_type, etag));
+        
+        self.persist(&key, &entry).await;
+        self.backend.insert(key, entry).await;
+    }
+    
+    /// Returns the cache entry for a key, running `fetch` to fill it on a miss
+    /// Concurrent callers for the same key share a single in-flight fetch; errors are
+    /// handed to every waiting caller and are not cached
+    pub async fn get_or_fetch<F, E>(&self, key: &str, fetch: F) -> Result<Arc<CacheEntry>, Arc<E>>
+    where
+        F: Future<Output = Result<CacheEntry, E>>,
+        E: Send + Sync + 'static,
+    {
+        debug!("Fetching into cache (coalesced): {}", key);
+        let result = self.in_flight
+            .try_get_with(key.to_string(), async move {
+                // Another caller may have finished the same fetch since our lookup
+                if let Some((entry, false)) = self.backend.get(key).await {
+                    return Ok(entry);
+                }
+                let entry = Arc::new(fetch.await?);
+                self.persist(key, &entry).await;
+                self.backend.insert(key.to_string(), entry.clone()).await;
+                self.backend.remove_negative(key).await;
+                Ok(entry)
+            })
+            .await;
+        self.in_flight.invalidate(key).await;
+        result
+    }
+    
+    /// Returns the validated icons of a site, running `discover` to find them on a miss
+    /// Concurrent callers for the same site share a single discovery; errors are not cached
+    pub async fn get_or_discover<F, E>(&self, site: &str, discover: F) -> Result<Arc<Vec<Icon>>, Arc<E>>
+    where
+        F: Future<Output = Result<Vec<Icon>, E>>,
+        E: Send + Sync + 'static,
+    {
+        let mut missed = false;
+        let result = self.discovery_cache
+            .try_get_with(site.to_string(), async {
+                debug!("Discovery cache miss for site: {}", site);
+                missed = true;
+                discover.await.map(Arc::new)
+            })
+            .await;
+        let lookup = if missed { CacheLookup::Miss } else { CacheLookup::Hit };
+        metrics().record_cache_lookup("discovery", lookup);
+        result
+    }
+    
+    /// Looks up an entry for inspection, without counting it as an access or checking the disk tier
+    pub async fn peek(&self, key: &str) -> Option<(Arc<CacheEntry>, bool)> {
+        self.backend.peek(key).await
+    }
+    
+    /// Checks if the disk tier holds a key, or None if no disk tier is configured
+    pub fn on_disk(&self, key: &str) -> Option<bool> {
+        self.disk.as_ref().map(|disk| disk.contains(key))
+    }
+    
+    /// Removes every key that matches from all tiers: main, expired and negative entries,
+    /// discovered icon lists and the disk tier
+    /// Returns how many (cache entries, discovered sites, disk entries) were removed
+    pub async fn purge(&self, matches: impl Fn(&str) -> bool + Sync) -> (u64, u64, u64) {
+        let removed = self.backend.purge(&matches).await;
+        
+        let sites: Vec<Arc<String>> = self.discovery_cache.iter().map(|(site, _)| site).filter(|site| matches(site)).collect();
+        for site in &sites {
+            self.discovery_cache.invalidate(site.as_str()).await;
+        }
+        
+        let disk_removed = match &self.disk {
+            Some(disk) => disk.remove_matching(&matches).await,
+            None => 0,
+        };
+        
+        info!("Purged {} cache entries, {} discovered sites and {} disk entries", removed, sites.len(), disk_removed);
+        (removed, sites.len() as u64, disk_removed)
     }
     
-    /// Manually move expired entries from main cache to expired cache
-    /// This is used as a workaround for the lack of direct on_evict handler capture support
+    /// Runs the backend's periodic maintenance, such as moving entries past their TTL
+    /// to the expired cache
     pub async fn check_and_move_expired_entries(&self) {
-        // This would require additional tracking of entry insertion times
-        // which is beyond the scope of the current implementation
-        // In a real implementation, we would iterate through main_cache entries 
-        // and check if they're approaching expiry
+        self.backend.sweep().await;
     }
     
-    /// Remove an entry from the expired cache
+    /// Remove the stale copy of an entry
     /// Called after successfully refreshing an entry
     pub async fn remove_from_expired(&self, key: &str) {
-        debug!("Removing from expired cache: {}", key);
-        self.expired_cache.invalidate(key).await;
+        self.backend.remove_stale(key).await;
     }
     
-    /// Insert a negative entry for failed URLs to avoid repeated validation attempts
+    /// Records a failed lookup, backing off longer the more often the key failed in a row
+    pub async fn record_failure(&self, key: String, kind: FailureKind) -> NegativeEntry {
+        let previous = self.backend.get_negative(&key).await;
+        let entry = NegativeEntry::after(previous.as_ref(), kind);
+        self.backend.insert_negative(key, entry.clone()).await;
+        entry
+    }
+    
+    /// Insert a negative entry for a URL without valid icons to avoid repeated validation attempts
     pub async fn insert_negative(&self, key: String) {
-        debug!("Inserting negative cache entry: {}", key);
-        self.negative_cache.insert(key, ()).await;
+        self.record_failure(key, FailureKind::NoIcons).await;
+    }
+    
+    /// Returns the failure a key is backing off from, if it isn't due for a retry yet
+    pub async fn negative(&self, key: &str) -> Option<NegativeEntry> {
+        self.backend.get_negative(key).await.filter(NegativeEntry::is_active)
+    }
+    
+    /// Returns the last recorded failure of a key, including ones that are due for a retry
+    pub async fn last_failure(&self, key: &str) -> Option<NegativeEntry> {
+        self.backend.get_negative(key).await
     }
     
     /// Check if a URL is in the negative cache
     pub async fn is_negative(&self, key: &str) -> bool {
-        self.negative_cache.get(key).await.is_some()
+        self.negative(key).await.is_some()
     }
     
-    /// Get cache statistics
-    pub async fn stats(&self) -> (u64, u64, u64) {
-        let main_count = self.main_cache.entry_count();
-        let expired_count = self.expired_cache.entry_count();
-        let negative_count = self.negative_cache.entry_count();
-        (main_count, expired_count, negative_count)
+    /// Get the number of entries and total bytes in the disk tier, if one is configured
+    pub fn disk_stats(&self) -> Option<(u64, u64)> {
+        self.disk.as_ref().map(|disk| disk.stats())
+    }
+    
+    /// Get the configured and current byte usage, if the backend is bounded in bytes
+    pub async fn byte_usage(&self) -> Option<ByteUsage> {
+        self.backend.byte_usage().await
+    }
+    
+    /// Get the number of (main, expired, negative) entries, if the backend counts them cheaply
+    pub async fn stats(&self) -> Option<(u64, u64, u64)> {
+        self.backend.stats().await
+    }
+    
+    /// Name of the backend entries are stored in; anything but "memory" is shared between replicas
+    pub fn backend_name(&self) -> &'static str {
+        self.backend.name()
+    }
+    
+    /// Get the number of sites in the discovery cache
+    pub async fn discovery_count(&self) -> u64 {
+        self.discovery_cache.run_pending_tasks().await;
+        self.discovery_cache.entry_count()
     }
-}
-
-/// Create a default icon cache with reasonable defaults
-pub fn create_default_icon_cache() -> IconCache {
-    // Default: 2000 entries, 2 hour TTL (increased from 1 hour)
-    let cache = IconCache::new(2000, 7200);
-    info!("Created optimized icon cache with dual-layer caching (2-hour main TTL, 3-day expired TTL)");
-    cache
 }
@@ -0,0 +1,390 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use crate::cache::{DEFAULT_MAX_BYTES, DEFAULT_MAX_CAPACITY, DEFAULT_TTL_SECONDS, STALE_TTL_SECONDS};
+use crate::disk_cache::DEFAULT_DISK_CACHE_MAX_BYTES;
+use crate::fallback::Fallback;
+use crate::redis_cache::{RedisConfig, DEFAULT_COMMAND_TIMEOUT_SECONDS, DEFAULT_MAX_CONNECTIONS};
+use log::info;
+
+/// Environment variable holding the path of the TOML configuration file
+pub const CONFIG_FILE_ENV: &str = "GETICON_CONFIG";
+
+/// Configuration file read from the working directory when GETICON_CONFIG isn't set, if it exists
+pub const DEFAULT_CONFIG_FILE: &str = "geticon.toml";
+
+/// Server settings, loaded from a TOML file with GETICON_* environment overrides
+/// Every field has a default, so the file and all of its sections are optional
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub http: HttpConfig,
+    pub cache: CacheConfig,
+    pub discovery: DiscoveryConfig,
+    pub batch: BatchConfig,
+    pub client_cache: ClientCacheConfig,
+    pub fallback: FallbackConfig,
+    pub admin: AdminConfig,
+    pub security: SecurityConfig,
+}
+
+/// Where the server listens
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: "0.0.0.0".to_string(),
+            port: 8080,
+        }
+    }
+}
+
+/// Outgoing HTTP client used to fetch pages and icons
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub timeout_seconds: u64,
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout_seconds: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            timeout_seconds: 10,
+            pool_max_idle_per_host: 10,
+            pool_idle_timeout_seconds: 30,
+        }
+    }
+}
+
+/// In-memory cache limits and lifetimes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub max_mb: u64,            // Byte budget of each in-memory layer
+    pub max_capacity: u64,      // Entries in the discovery cache, and in caches bounded by entry count
+    pub ttl_seconds: u64,       // How long entries are served as fresh
+    pub stale_ttl_seconds: u64, // How long entries are served stale after that
+    pub redis: RedisCacheConfig,
+    pub disk: DiskCacheConfig,
+}
+
+impl CacheConfig {
+    pub fn max_bytes(&self) -> u64 {
+        self.max_mb.saturating_mul(1024 * 1024)
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_mb: DEFAULT_MAX_BYTES / 1024 / 1024,
+            max_capacity: DEFAULT_MAX_CAPACITY,
+            ttl_seconds: DEFAULT_TTL_SECONDS,
+            stale_ttl_seconds: STALE_TTL_SECONDS,
+            redis: RedisCacheConfig::default(),
+            disk: DiskCacheConfig::default(),
+        }
+    }
+}
+
+/// Redis server shared by all replicas; the cache stays in memory without one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisCacheConfig {
+    pub url: Option<String>,           // redis://[[user]:password@]host[:port][/db]
+    pub command_timeout_seconds: u64,  // How long connecting or running a single command may take
+    pub max_connections: usize,        // Connections open at once; further commands wait for one
+}
+
+impl Default for RedisCacheConfig {
+    fn default() -> Self {
+        RedisCacheConfig {
+            url: None,
+            command_timeout_seconds: DEFAULT_COMMAND_TIMEOUT_SECONDS,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        }
+    }
+}
+
+impl RedisCacheConfig {
+    /// The parsed server URL, if one is set and valid
+    pub fn server(&self) -> Option<RedisConfig> {
+        self.url.as_deref().and_then(RedisConfig::from_url)
+    }
+}
+
+/// Persistent on-disk cache tier, disabled without a directory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiskCacheConfig {
+    pub dir: Option<String>,
+    pub max_mb: u64,
+}
+
+impl DiskCacheConfig {
+    pub fn max_bytes(&self) -> u64 {
+        self.max_mb.saturating_mul(1024 * 1024)
+    }
+}
+
+impl Default for DiskCacheConfig {
+    fn default() -> Self {
+        DiskCacheConfig {
+            dir: None,
+            max_mb: DEFAULT_DISK_CACHE_MAX_BYTES / 1024 / 1024,
+        }
+    }
+}
+
+/// Limits on how much work discovering a site's icons may do
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    pub max_concurrent_validations: usize, // Icon requests in flight at once while validating
+    pub max_redirects: u8,                  // Meta refresh hops followed before giving up
+    pub validation_cutoff: usize,           // Best-scoring candidates validated per page
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            max_concurrent_validations: 5,
+            max_redirects: 5,
+            validation_cutoff: 5,
+        }
+    }
+}
+
+/// Limits on /batch and /admin/prewarm requests
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    pub max_urls: usize,      // URLs accepted in a single request
+    pub concurrency: usize,   // URLs from one request looked up at the same time
+    pub timeout_seconds: u64, // Time limit of a whole /batch request, after which unfinished URLs time out
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_urls: 250,
+            concurrency: 16,
+            timeout_seconds: 25,
+        }
+    }
+}
+
+/// Cache-Control max-ages sent to clients
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientCacheConfig {
+    pub max_age_seconds: u64,          // Fresh icons and /json documents
+    pub stale_max_age_seconds: u64,    // Stale entries served while a refresh runs
+    pub fallback_max_age_seconds: u64, // Fallback images, so a real icon replaces them soon
+}
+
+impl Default for ClientCacheConfig {
+    fn default() -> Self {
+        ClientCacheConfig {
+            max_age_seconds: 7200,
+            stale_max_age_seconds: 600,
+            fallback_max_age_seconds: 3600,
+        }
+    }
+}
+
+/// What /img serves when a request has no `fallback` parameter
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FallbackConfig {
+    pub default: Option<String>,          // 404, letter, blank or an image URL
+    pub theme_color_timeout_seconds: u64, // How long fetching a page for a letter avatar's theme-color may take
+}
+
+impl Default for FallbackConfig {
+    fn default() -> Self {
+        FallbackConfig {
+            default: None,
+            theme_color_timeout_seconds: 3,
+        }
+    }
+}
+
+impl FallbackConfig {
+    /// The parsed default fallback, if one is set and valid
+    pub fn default_fallback(&self) -> Option<Fallback> {
+        self.default.as_deref().and_then(Fallback::from_param)
+    }
+}
+
+/// The /admin routes, disabled without a token
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub token: Option<String>, // Bearer token required by every admin request
+}
+
+/// Address guard settings
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    pub allow_private_addresses: bool, // Allow private, loopback and link-local addresses (internal deployments only)
+}
+
+/// Why the configuration couldn't be loaded
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "Failed to read config file {}: {}", path, err),
+            ConfigError::Parse(message) => write!(f, "Invalid config file: {}", message),
+            ConfigError::Invalid(message) => write!(f, "Invalid configuration: {}", message),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl Config {
+    /// Loads the configuration file named by GETICON_CONFIG (or ./geticon.toml if present),
+    /// applies GETICON_* environment overrides and validates the result
+    pub fn load() -> Result<Self, ConfigError> {
+        let path = match std::env::var(CONFIG_FILE_ENV) {
+            Ok(path) if !path.trim().is_empty() => Some(path.trim().to_string()),
+            _ => Some(DEFAULT_CONFIG_FILE.to_string()).filter(|path| Path::new(path).exists()),
+        };
+
+        let mut config = match &path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|err| ConfigError::Read(path.clone(), err))?;
+                info!("Loading configuration from {}", path);
+                Self::from_toml(&text)?
+            },
+            None => Config::default(),
+        };
+        config.apply_overrides(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses a TOML configuration; missing sections and fields keep their defaults
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(|err| ConfigError::Parse(err.to_string()))
+    }
+
+    /// Overrides settings with the GETICON_* variables returned by `lookup`
+    pub fn apply_overrides(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let lookup = |name: &str| lookup(name).map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+
+        if let Some(address) = lookup("GETICON_BIND_ADDRESS") {
+            self.server.bind_address = address;
+        }
+        override_value(&lookup, "GETICON_PORT", &mut self.server.port)?;
+        override_value(&lookup, "GETICON_HTTP_TIMEOUT_SECONDS", &mut self.http.timeout_seconds)?;
+        override_value(&lookup, "GETICON_HTTP_POOL_MAX_IDLE_PER_HOST", &mut self.http.pool_max_idle_per_host)?;
+        override_value(&lookup, "GETICON_HTTP_POOL_IDLE_TIMEOUT_SECONDS", &mut self.http.pool_idle_timeout_seconds)?;
+        override_value(&lookup, "GETICON_CACHE_MAX_MB", &mut self.cache.max_mb)?;
+        override_value(&lookup, "GETICON_CACHE_MAX_CAPACITY", &mut self.cache.max_capacity)?;
+        override_value(&lookup, "GETICON_CACHE_TTL_SECONDS", &mut self.cache.ttl_seconds)?;
+        override_value(&lookup, "GETICON_CACHE_STALE_TTL_SECONDS", &mut self.cache.stale_ttl_seconds)?;
+        override_value(&lookup, "GETICON_MAX_CONCURRENT_VALIDATIONS", &mut self.discovery.max_concurrent_validations)?;
+        override_value(&lookup, "GETICON_MAX_REDIRECTS", &mut self.discovery.max_redirects)?;
+        override_value(&lookup, "GETICON_VALIDATION_CUTOFF", &mut self.discovery.validation_cutoff)?;
+        override_value(&lookup, "GETICON_BATCH_MAX_URLS", &mut self.batch.max_urls)?;
+        override_value(&lookup, "GETICON_BATCH_CONCURRENCY", &mut self.batch.concurrency)?;
+        override_value(&lookup, "GETICON_BATCH_TIMEOUT_SECONDS", &mut self.batch.timeout_seconds)?;
+        override_value(&lookup, "GETICON_CLIENT_MAX_AGE_SECONDS", &mut self.client_cache.max_age_seconds)?;
+        override_value(&lookup, "GETICON_CLIENT_STALE_MAX_AGE_SECONDS", &mut self.client_cache.stale_max_age_seconds)?;
+        override_value(&lookup, "GETICON_CLIENT_FALLBACK_MAX_AGE_SECONDS", &mut self.client_cache.fallback_max_age_seconds)?;
+        if let Some(url) = lookup("GETICON_REDIS_URL") {
+            self.cache.redis.url = Some(url);
+        }
+        override_value(&lookup, "GETICON_REDIS_COMMAND_TIMEOUT_SECONDS", &mut self.cache.redis.command_timeout_seconds)?;
+        override_value(&lookup, "GETICON_REDIS_MAX_CONNECTIONS", &mut self.cache.redis.max_connections)?;
+        if let Some(dir) = lookup("GETICON_DISK_CACHE_DIR") {
+            self.cache.disk.dir = Some(dir);
+        }
+        override_value(&lookup, "GETICON_DISK_CACHE_MAX_MB", &mut self.cache.disk.max_mb)?;
+        if let Some(fallback) = lookup("GETICON_DEFAULT_FALLBACK") {
+            self.fallback.default = Some(fallback);
+        }
+        override_value(&lookup, "GETICON_THEME_COLOR_TIMEOUT_SECONDS", &mut self.fallback.theme_color_timeout_seconds)?;
+        if let Some(token) = lookup("GETICON_ADMIN_TOKEN") {
+            self.admin.token = Some(token);
+        }
+        if let Some(value) = lookup("GETICON_ALLOW_PRIVATE_ADDRESSES") {
+            self.security.allow_private_addresses = match value.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" => true,
+                "0" | "false" | "no" => false,
+                _ => return Err(ConfigError::Invalid(format!("GETICON_ALLOW_PRIVATE_ADDRESSES has an invalid value: {}", value))),
+            };
+        }
+        Ok(())
+    }
+
+    /// Rejects settings the server can't run with
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let checks = [
+            (self.server.bind_address.trim().is_empty(), "server.bind_address must not be empty"),
+            (self.http.timeout_seconds == 0, "http.timeout_seconds must be at least 1"),
+            (self.cache.max_mb == 0, "cache.max_mb must be at least 1"),
+            (self.cache.max_capacity == 0, "cache.max_capacity must be at least 1"),
+            (self.cache.ttl_seconds == 0, "cache.ttl_seconds must be at least 1"),
+            (self.discovery.max_concurrent_validations == 0, "discovery.max_concurrent_validations must be at least 1"),
+            (self.discovery.validation_cutoff == 0, "discovery.validation_cutoff must be at least 1"),
+            (self.batch.max_urls == 0, "batch.max_urls must be at least 1"),
+            (self.batch.concurrency == 0, "batch.concurrency must be at least 1"),
+            (self.batch.timeout_seconds == 0, "batch.timeout_seconds must be at least 1"),
+            (self.cache.redis.url.is_some() && self.cache.redis.server().is_none(),
+                "cache.redis.url must look like redis://[[user]:password@]host[:port][/db]"),
+            (self.cache.redis.command_timeout_seconds == 0, "cache.redis.command_timeout_seconds must be at least 1"),
+            (self.cache.redis.max_connections == 0, "cache.redis.max_connections must be at least 1"),
+            (self.cache.disk.dir.as_deref().is_some_and(|dir| dir.trim().is_empty()), "cache.disk.dir must not be empty"),
+            (self.cache.disk.max_mb == 0, "cache.disk.max_mb must be at least 1"),
+            (self.fallback.default.is_some() && self.fallback.default_fallback().is_none(),
+                "fallback.default must be 404, letter, blank or an http(s) image URL"),
+            (self.fallback.theme_color_timeout_seconds == 0, "fallback.theme_color_timeout_seconds must be at least 1"),
+            (self.admin.token.as_deref().is_some_and(|token| token.trim().is_empty()), "admin.token must not be empty"),
+        ];
+        match checks.iter().find(|(failed, _)| *failed) {
+            Some((_, message)) => Err(ConfigError::Invalid(message.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Address the server binds to
+    pub fn bind_address(&self) -> (&str, u16) {
+        (self.server.bind_address.as_str(), self.server.port)
+    }
+}
+
+/// Replaces `target` with the parsed value of an environment variable, if it is set
+fn override_value<T: FromStr>(
+    lookup: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    target: &mut T
+) -> Result<(), ConfigError> {
+    if let Some(value) = lookup(name) {
+        *target = value
+            .parse()
+            .map_err(|_| ConfigError::Invalid(format!("{} has an invalid value: {}", name, value)))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use log::debug;
+use crate::ico::parse_ico_directory;
+
+/// Reads the real pixel dimensions of an image from its header bytes
+/// Supports PNG, GIF, JPEG, WebP, ICO/CUR and SVG; only the first few KB are needed
+/// for most formats, JPEG may need more if large metadata segments precede the frame header
+pub fn sniff_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let dimensions = if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_dimensions(bytes)
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        gif_dimensions(bytes)
+    } else if bytes.starts_with(b"\xFF\xD8") {
+        jpeg_dimensions(bytes)
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        webp_dimensions(bytes)
+    } else if bytes.starts_with(b"\x00\x00\x01\x00") || bytes.starts_with(b"\x00\x00\x02\x00") {
+        ico_dimensions(bytes)
+    } else {
+        svg_dimensions(bytes)
+    };
+
+    // Zero-sized images are treated as unknown rather than trusted
+    let dimensions = dimensions.filter(|(w, h)| *w > 0 && *h > 0);
+    debug!("Sniffed image dimensions: {:?}", dimensions);
+    dimensions
+}
+
+fn be_u16(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([b[0], b[1]]) as u32)
+}
+
+fn le_u16(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([b[0], b[1]]) as u32)
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn le_u24(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset + 3)?;
+    Some(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16)
+}
+
+/// PNG: width and height live in the IHDR chunk right after the signature
+fn png_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    if bytes.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    Some((be_u32(bytes, 16)?, be_u32(bytes, 20)?))
+}
+
+/// GIF: logical screen size follows the 6-byte signature
+fn gif_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    Some((le_u16(bytes, 6)?, le_u16(bytes, 8)?))
+}
+
+/// JPEG: walk the marker segments until the first start-of-frame (SOFn) marker
+fn jpeg_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let mut pos = 2;
+    loop {
+        // Skip to the next marker, ignoring fill bytes
+        while *bytes.get(pos)? != 0xFF {
+            pos += 1;
+        }
+        while *bytes.get(pos)? == 0xFF {
+            pos += 1;
+        }
+        let marker = *bytes.get(pos)?;
+        pos += 1;
+
+        match marker {
+            // Standalone markers without a length field
+            0x01 | 0xD0..=0xD7 => continue,
+            // End of image or start of scan before any frame header
+            0xD9 | 0xDA => return None,
+            // SOF0-SOF15, excluding DHT (C4), JPG (C8) and DAC (CC)
+            0xC0..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                let height = be_u16(bytes, pos + 3)?;
+                let width = be_u16(bytes, pos + 5)?;
+                return Some((width, height));
+            },
+            _ => {
+                let length = be_u16(bytes, pos)? as usize;
+                if length < 2 {
+                    return None;
+                }
+                pos += length;
+            }
+        }
+    }
+}
+
+/// WebP: lossy (VP8), lossless (VP8L) and extended (VP8X) variants store sizes differently
+fn webp_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    match bytes.get(12..16)? {
+        b"VP8 " => {
+            // Key frame start code precedes the 14-bit width and height
+            if bytes.get(23..26)? != [0x9D, 0x01, 0x2A] {
+                return None;
+            }
+            Some((le_u16(bytes, 26)? & 0x3FFF, le_u16(bytes, 28)? & 0x3FFF))
+        },
+        b"VP8L" => {
+            if *bytes.get(20)? != 0x2F {
+                return None;
+            }
+            let b = bytes.get(21..25)?;
+            let width = 1 + (b[0] as u32 | ((b[1] as u32 & 0x3F) << 8));
+            let height = 1 + ((b[1] as u32 >> 6) | ((b[2] as u32) << 2) | ((b[3] as u32 & 0x0F) << 10));
+            Some((width, height))
+        },
+        b"VP8X" => Some((1 + le_u24(bytes, 24)?, 1 + le_u24(bytes, 27)?)),
+        _ => None,
+    }
+}
+
+/// ICO/CUR: reports the largest image listed in the icon directory
+fn ico_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    parse_ico_directory(bytes)?
+        .into_iter()
+        .map(|frame| (frame.width, frame.height))
+        .max_by_key(|(w, h)| (*w).max(*h))
+}
+
+/// SVG: uses absolute width/height attributes on the root element, falling back to viewBox
+fn svg_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let text = String::from_utf8_lossy(&bytes[..bytes.len().min(8192)]);
+    let start = text.find("<svg")?;
+    let end = start + text[start..].find('>')?;
+    let tag = &text[start..end];
+
+    let width = svg_attribute(tag, "width").and_then(parse_svg_length);
+    let height = svg_attribute(tag, "height").and_then(parse_svg_length);
+    if let (Some(w), Some(h)) = (width, height) {
+        return Some((w, h));
+    }
+
+    let view_box = svg_attribute(tag, "viewBox")?;
+    let values: Vec<f32> = view_box
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| s.parse().ok())
+        .collect();
+    if values.len() != 4 || values[2] <= 0.0 || values[3] <= 0.0 {
+        return None;
+    }
+    Some((values[2].ceil() as u32, values[3].ceil() as u32))
+}
+
+fn svg_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut search = tag;
+    while let Some(idx) = search.find(name) {
+        let before = search[..idx].chars().last();
+        let rest = &search[idx + name.len()..];
+        let rest_trimmed = rest.trim_start();
+        // Only match whole attribute names, e.g. not "stroke-width"
+        if before.is_some_and(|c| c.is_whitespace()) && rest_trimmed.starts_with('=') {
+            let value = rest_trimmed[1..].trim_start();
+            let quote = value.chars().next()?;
+            if quote == '"' || quote == '\'' {
+                let value = &value[1..];
+                return value.find(quote).map(|end| &value[..end]);
+            }
+        }
+        search = rest;
+    }
+    None
+}
+
+fn parse_svg_length(value: &str) -> Option<u32> {
+    let value = value.trim();
+    // Relative units like % or em don't describe an intrinsic size
+    let number = value.strip_suffix("px").unwrap_or(value);
+    let parsed: f32 = number.trim().parse().ok()?;
+    if parsed > 0.0 {
+        Some(parsed.ceil() as u32)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,327 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::cache::{CacheEntry, UpstreamValidators};
+use crate::config::DiskCacheConfig;
+use tokio::io::AsyncReadExt;
+use log::{debug, info, warn};
+
+/// Size limit used when `cache.disk.max_mb` is not set (512 MB)
+pub const DEFAULT_DISK_CACHE_MAX_BYTES: u64 = 512 * 1024 * 1024;
+
+/// File extension of cache entry files
+const ENTRY_EXTENSION: &str = "entry";
+
+/// File extension of entries that are still being written
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Metadata stored in front of the content in every entry file
+#[derive(Serialize, Deserialize)]
+struct EntryMeta {
+    key: String,
+    content_type: String,
+    etag: String,
+    stored_at: u64, // Seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upstream: Option<UpstreamValidators>,
+}
+
+/// An entry read back from disk
+pub struct DiskEntry {
+    pub key: String,
+    pub content: Bytes,
+    pub content_type: String,
+    pub etag: String,
+    /// Time since the content was fetched
+    pub age: Duration,
+    pub upstream: Option<UpstreamValidators>,
+}
+
+/// What the in-memory index knows about each file on disk
+struct IndexEntry {
+    key: String,
+    size: u64,
+    stored_at: u64,
+    sequence: u64, // Write order within this process, breaks ties between equal timestamps
+}
+
+/// The entries on disk by file stem, with their total size kept up to date on every change
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, IndexEntry>,
+    bytes: u64,
+}
+
+impl Index {
+    fn insert(&mut self, stem: String, entry: IndexEntry) {
+        self.bytes += entry.size;
+        if let Some(replaced) = self.entries.insert(stem, entry) {
+            self.bytes -= replaced.size;
+        }
+    }
+
+    fn remove(&mut self, stem: &str) -> Option<IndexEntry> {
+        let entry = self.entries.remove(stem)?;
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+}
+
+/// Persistent cache tier storing icon bytes, content type and ETag as files in a directory
+/// Bounded by total size; the oldest entries are removed first when the limit is exceeded
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<Index>,
+    writes: AtomicU64,
+}
+
+impl DiskCache {
+    /// Opens (creating if needed) a disk cache in the given directory and indexes existing entries
+    /// Only the metadata of each entry is read; the content stays on disk until it is requested
+    pub async fn open(dir: impl AsRef<Path>, max_bytes: u64) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let mut index = Index::default();
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(ENTRY_EXTENSION) => {},
+                Some(TEMP_EXTENSION) => {
+                    // Left behind by a write that never finished
+                    let _ = tokio::fs::remove_file(&path).await;
+                    continue;
+                },
+                _ => continue,
+            }
+            let Some(stem) = path.file_stem().and_then(|s| s.to_str()).map(str::to_string) else {
+                continue;
+            };
+            match read_meta(&path).await {
+                Some((meta, size)) => {
+                    index.insert(stem, IndexEntry { key: meta.key, size, stored_at: meta.stored_at, sequence: 0 });
+                },
+                None => {
+                    warn!("Removing unreadable disk cache entry: {}", path.display());
+                    let _ = tokio::fs::remove_file(&path).await;
+                }
+            }
+        }
+
+        let cache = DiskCache { dir, max_bytes, index: Mutex::new(index), writes: AtomicU64::new(1) };
+        let (count, bytes) = cache.stats();
+        info!("Opened disk cache at {} with {} entries ({} bytes, limit {} bytes)",
+            cache.dir.display(), count, bytes, max_bytes);
+        cache.enforce_limit().await;
+        Ok(cache)
+    }
+
+    /// Opens the disk cache configured in `cache.disk`, if any
+    pub async fn from_config(config: &DiskCacheConfig) -> Option<Self> {
+        let dir = config.dir.as_deref()?;
+        match DiskCache::open(dir, config.max_bytes()).await {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                warn!("Failed to open disk cache at {}, continuing without it: {}", dir, err);
+                None
+            }
+        }
+    }
+
+    fn path_for(&self, stem: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", stem, ENTRY_EXTENSION))
+    }
+
+    /// Reads an entry by cache key
+    pub async fn get(&self, key: &str) -> Option<DiskEntry> {
+        let stem = stem_for(key);
+        if !self.index.lock().unwrap().entries.contains_key(&stem) {
+            return None;
+        }
+        let entry = read_entry(&self.path_for(&stem)).await;
+        match entry {
+            Some(entry) if entry.key == key => {
+                debug!("Disk cache hit for key: {}", key);
+                Some(entry)
+            },
+            _ => {
+                debug!("Disk cache entry missing or unreadable for key: {}", key);
+                self.remove(key).await;
+                None
+            }
+        }
+    }
+
+    /// Writes a cache entry including its origin validators, replacing any previous one for the same key
+    pub async fn insert_entry(&self, key: &str, entry: &CacheEntry) {
+        let stem = stem_for(key);
+        let content = &entry.content;
+        let meta = EntryMeta {
+            key: key.to_string(),
+            content_type: entry.content_type.clone(),
+            etag: entry.etag.clone(),
+            stored_at: now_secs(),
+            upstream: entry.upstream.clone(),
+        };
+        let meta_json = match serde_json::to_vec(&meta) {
+            Ok(json) => json,
+            Err(err) => {
+                warn!("Failed to serialize disk cache metadata for {}: {}", key, err);
+                return;
+            }
+        };
+
+        // Layout: metadata length (u32 LE), metadata JSON, content
+        let mut data = Vec::with_capacity(4 + meta_json.len() + content.len());
+        data.extend_from_slice(&(meta_json.len() as u32).to_le_bytes());
+        data.extend_from_slice(&meta_json);
+        data.extend_from_slice(content);
+
+        // Write to a temporary file first so readers never see a partial entry
+        // Concurrent writes of the same key (or from another process) each get their own file
+        let sequence = self.writes.fetch_add(1, Ordering::Relaxed);
+        let path = self.path_for(&stem);
+        let tmp_path = self.dir.join(format!("{}.{}.{}.{}", stem, std::process::id(), sequence, TEMP_EXTENSION));
+        let written = match tokio::fs::write(&tmp_path, &data).await {
+            Ok(()) => tokio::fs::rename(&tmp_path, &path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = written {
+            warn!("Failed to write disk cache entry for {}: {}", key, err);
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return;
+        }
+
+        debug!("Stored {} bytes in disk cache for key: {}", data.len(), key);
+        self.index.lock().unwrap().insert(stem, IndexEntry { key: meta.key, size: data.len() as u64, stored_at: meta.stored_at, sequence });
+        self.enforce_limit().await;
+    }
+
+    /// Removes an entry by cache key
+    pub async fn remove(&self, key: &str) {
+        let stem = stem_for(key);
+        if self.index.lock().unwrap().remove(&stem).is_some() {
+            let _ = tokio::fs::remove_file(self.path_for(&stem)).await;
+        }
+    }
+
+    /// Checks if an entry is stored for a cache key
+    pub fn contains(&self, key: &str) -> bool {
+        self.index.lock().unwrap().entries.contains_key(&stem_for(key))
+    }
+
+    /// Removes every entry whose key matches, returning how many were removed
+    pub async fn remove_matching(&self, matches: &(dyn Fn(&str) -> bool + Sync)) -> u64 {
+        let stems: Vec<String> = {
+            let mut index = self.index.lock().unwrap();
+            let stems: Vec<String> = index.entries.iter().filter(|(_, e)| matches(&e.key)).map(|(stem, _)| stem.clone()).collect();
+            for stem in &stems {
+                index.remove(stem);
+            }
+            stems
+        };
+        for stem in &stems {
+            let _ = tokio::fs::remove_file(self.path_for(stem)).await;
+        }
+        stems.len() as u64
+    }
+
+    /// Returns the cache keys of all entries, most recently stored first
+    /// Used to warm the memory cache at startup without reading every entry at once
+    pub fn keys_newest_first(&self) -> Vec<String> {
+        let index = self.index.lock().unwrap();
+        let mut entries: Vec<&IndexEntry> = index.entries.values().collect();
+        entries.sort_by_key(|e| std::cmp::Reverse((e.stored_at, e.sequence)));
+        entries.into_iter().map(|e| e.key.clone()).collect()
+    }
+
+    /// Returns the number of entries and their total size in bytes
+    pub fn stats(&self) -> (u64, u64) {
+        let index = self.index.lock().unwrap();
+        (index.entries.len() as u64, index.bytes)
+    }
+
+    /// Removes the oldest entries until the total size is within the limit
+    /// Only scans the index when the running total is over the limit
+    async fn enforce_limit(&self) {
+        let evicted: Vec<String> = {
+            let mut index = self.index.lock().unwrap();
+            if index.bytes <= self.max_bytes {
+                return;
+            }
+
+            let mut by_age: Vec<(String, (u64, u64))> = index
+                .entries
+                .iter()
+                .map(|(stem, e)| (stem.clone(), (e.stored_at, e.sequence)))
+                .collect();
+            by_age.sort_by_key(|(_, age)| *age);
+
+            let mut evicted = Vec::new();
+            for (stem, _) in by_age {
+                if index.bytes <= self.max_bytes {
+                    break;
+                }
+                index.remove(&stem);
+                evicted.push(stem);
+            }
+            evicted
+        };
+
+        debug!("Evicting {} entries from disk cache to stay within {} bytes", evicted.len(), self.max_bytes);
+        for stem in evicted {
+            let _ = tokio::fs::remove_file(self.path_for(&stem)).await;
+        }
+    }
+}
+
+/// File name stem for a cache key; keys contain URLs, so they are hashed
+fn stem_for(key: &str) -> String {
+    format!("{:x}", md5::compute(key.as_bytes()))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Splits an entry file into its metadata and content
+fn parse_entry(data: &[u8]) -> Option<(EntryMeta, &[u8])> {
+    let meta_len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let meta: EntryMeta = serde_json::from_slice(data.get(4..4 + meta_len)?).ok()?;
+    Some((meta, &data[4 + meta_len..]))
+}
+
+/// Reads the metadata at the start of an entry file, and the size of the whole file
+async fn read_meta(path: &Path) -> Option<(EntryMeta, u64)> {
+    let mut file = tokio::fs::File::open(path).await.ok()?;
+    let size = file.metadata().await.ok()?.len();
+    let mut meta_len = [0u8; 4];
+    file.read_exact(&mut meta_len).await.ok()?;
+    let meta_len = u32::from_le_bytes(meta_len) as u64;
+    if 4 + meta_len > size {
+        return None;
+    }
+    let mut meta = vec![0u8; meta_len as usize];
+    file.read_exact(&mut meta).await.ok()?;
+    Some((serde_json::from_slice(&meta).ok()?, size))
+}
+
+async fn read_entry(path: &Path) -> Option<DiskEntry> {
+    let data = tokio::fs::read(path).await.ok()?;
+    let (meta, content) = parse_entry(&data)?;
+    Some(DiskEntry {
+        key: meta.key,
+        content: Bytes::copy_from_slice(content),
+        content_type: meta.content_type,
+        etag: meta.etag,
+        age: Duration::from_secs(now_secs().saturating_sub(meta.stored_at)),
+        upstream: meta.upstream,
+    })
+}
@@ -0,0 +1,268 @@
+#[cfg(feature = "server")]
+use actix_web::{http::{header, StatusCode as HttpStatusCode}, HttpResponse};
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use url::Url;
+use crate::failure::{is_interrupted_transfer, FailureKind};
+use crate::security::BlockedAddressError;
+
+/// Why discovering, validating or fetching an icon failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetIconError {
+    /// A request parameter is missing or invalid
+    InvalidRequest(String),
+    /// The URL couldn't be parsed or normalized
+    InvalidUrl,
+    /// The host resolves to a private or internal address
+    BlockedHost(String),
+    /// The site's name didn't resolve
+    Dns,
+    /// The connection was refused or dropped
+    Connect,
+    /// The site didn't answer in time
+    Timeout,
+    /// The TLS handshake failed
+    Tls,
+    /// The connection dropped before the whole response was read
+    Interrupted,
+    /// HTTP or meta refresh redirects didn't end within the limit
+    RedirectLoop,
+    /// A meta refresh tag points at a URL that can't be parsed
+    InvalidRedirect(String),
+    /// The origin answered with an error status
+    HttpStatus(u16),
+    /// The content isn't an image (HTML, a non-image content type or a corrupt file)
+    NotAnImage(String),
+    /// The origin sent no content
+    Empty,
+    /// The icon is larger than `MAX_ICON_BYTES`
+    TooLarge(u64),
+    /// The site has no usable icon
+    NoIcons,
+    /// The lookup failed recently and is backing off; holds the failure and seconds until the next try
+    Backoff(FailureKind, u64),
+    /// Something went wrong on our side
+    Internal(String),
+}
+
+/// Content type of error responses, as defined by RFC 9457
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// Body of error responses: an RFC 9457 problem document, extended with
+/// the stable error code, the normalized URL and when to retry
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// `urn:geticon:error:<code>`
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    pub code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Seconds until the lookup is worth retrying
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl GetIconError {
+    /// Classifies a failed request to an origin
+    pub fn from_request_error(err: &reqwest::Error) -> Self {
+        if let Some(blocked) = blocked_address(err) {
+            return blocked.into();
+        }
+        if let Some(kind) = FailureKind::from_request_error(err) {
+            return match (kind, err.status()) {
+                (FailureKind::NoIcons, Some(status)) => GetIconError::HttpStatus(status.as_u16()),
+                (FailureKind::InvalidContent, _) => GetIconError::NotAnImage(err.to_string()),
+                (kind, _) => kind.into(),
+            };
+        }
+        if err.is_redirect() {
+            GetIconError::RedirectLoop
+        } else if let Some(status) = err.status() {
+            GetIconError::HttpStatus(status.as_u16())
+        } else if is_interrupted_transfer(err) {
+            GetIconError::Interrupted
+        } else if err.is_builder() {
+            GetIconError::InvalidUrl
+        } else {
+            GetIconError::Internal(err.to_string())
+        }
+    }
+
+    /// Stable snake_case code of the failure, for clients and metrics
+    pub fn code(&self) -> &'static str {
+        match self {
+            GetIconError::InvalidRequest(_) => "invalid_request",
+            GetIconError::InvalidUrl => "invalid_url",
+            GetIconError::BlockedHost(_) => "blocked_host",
+            GetIconError::Dns => "dns",
+            GetIconError::Connect => "connect",
+            GetIconError::Timeout => "timeout",
+            GetIconError::Tls => "tls",
+            GetIconError::Interrupted => "interrupted",
+            GetIconError::RedirectLoop => "redirect_loop",
+            GetIconError::InvalidRedirect(_) => "invalid_redirect",
+            GetIconError::HttpStatus(_) => "http_status",
+            GetIconError::NotAnImage(_) => "not_an_image",
+            GetIconError::Empty => "empty",
+            GetIconError::TooLarge(_) => "too_large",
+            GetIconError::NoIcons => "no_icons",
+            GetIconError::Backoff(kind, _) => GetIconError::from(*kind).code(),
+            GetIconError::Internal(_) => "internal",
+        }
+    }
+
+    /// Status returned for this failure: 404 when the site has no usable icon,
+    /// 502/504 when it couldn't be reached or misbehaved
+    pub fn status(&self) -> StatusCode {
+        match self {
+            GetIconError::InvalidRequest(_) | GetIconError::InvalidUrl => StatusCode::BAD_REQUEST,
+            GetIconError::BlockedHost(_) => StatusCode::FORBIDDEN,
+            GetIconError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            GetIconError::Dns | GetIconError::Connect | GetIconError::Tls | GetIconError::Interrupted => StatusCode::BAD_GATEWAY,
+            GetIconError::RedirectLoop | GetIconError::InvalidRedirect(_) => StatusCode::BAD_GATEWAY,
+            GetIconError::HttpStatus(status) if *status >= 500 => StatusCode::BAD_GATEWAY,
+            GetIconError::HttpStatus(_) => StatusCode::NOT_FOUND,
+            GetIconError::NotAnImage(_) | GetIconError::Empty | GetIconError::TooLarge(_) => StatusCode::NOT_FOUND,
+            GetIconError::NoIcons => StatusCode::NOT_FOUND,
+            GetIconError::Backoff(kind, _) => kind.status(),
+            GetIconError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The failure to remember in the negative cache, if the site is to blame
+    /// Blocked hosts, redirect problems, interrupted transfers and origin 5xx responses are not remembered
+    pub fn failure(&self) -> Option<FailureKind> {
+        match self {
+            GetIconError::Dns => Some(FailureKind::Dns),
+            GetIconError::Connect => Some(FailureKind::Connect),
+            GetIconError::Timeout => Some(FailureKind::Timeout),
+            GetIconError::Tls => Some(FailureKind::Tls),
+            GetIconError::HttpStatus(status) if (400..500).contains(status) => Some(FailureKind::NoIcons),
+            GetIconError::NoIcons => Some(FailureKind::NoIcons),
+            GetIconError::NotAnImage(_) | GetIconError::Empty | GetIconError::TooLarge(_) => Some(FailureKind::InvalidContent),
+            _ => None,
+        }
+    }
+
+    /// Short summary of the failure, the same for every occurrence of its code
+    pub fn title(&self) -> &'static str {
+        match self {
+            GetIconError::InvalidRequest(_) => "Invalid request",
+            GetIconError::InvalidUrl => "Invalid URL",
+            GetIconError::BlockedHost(_) => "Destination not allowed",
+            GetIconError::Dns => "Site could not be resolved",
+            GetIconError::Connect => "Site unreachable",
+            GetIconError::Timeout => "Site timed out",
+            GetIconError::Tls => "TLS handshake failed",
+            GetIconError::Interrupted => "Transfer interrupted",
+            GetIconError::RedirectLoop => "Too many redirects",
+            GetIconError::InvalidRedirect(_) => "Invalid redirect",
+            GetIconError::HttpStatus(_) => "Icon request failed",
+            GetIconError::NotAnImage(_) => "Not an image",
+            GetIconError::Empty => "Empty icon",
+            GetIconError::TooLarge(_) => "Icon too large",
+            GetIconError::NoIcons => "No icon found",
+            GetIconError::Backoff(kind, _) => GetIconError::from(*kind).title(),
+            GetIconError::Internal(_) => "Internal error",
+        }
+    }
+
+    /// Seconds until the lookup is worth retrying: the remaining backoff for failures served
+    /// from the negative cache, and the first backoff step for failures that are remembered
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            GetIconError::Backoff(_, seconds) => Some(*seconds),
+            _ => self.failure().map(|kind| kind.backoff(1).as_secs()),
+        }
+    }
+
+    /// The problem document describing this failure for a lookup of `url`
+    pub fn problem(&self, url: Option<&Url>) -> Problem {
+        Problem {
+            problem_type: format!("urn:geticon:error:{}", self.code()),
+            title: self.title(),
+            status: self.status().as_u16(),
+            detail: self.to_string(),
+            code: self.code(),
+            url: url.map(Url::to_string),
+            retry_after: self.retry_after(),
+        }
+    }
+
+    /// Problem response with the failure's status, and Retry-After when a retry is worthwhile
+    #[cfg(feature = "server")]
+    pub fn error_response(&self, url: Option<&Url>) -> HttpResponse {
+        // actix-web and reqwest depend on different versions of the http crate
+        let status = HttpStatusCode::from_u16(self.status().as_u16()).unwrap_or(HttpStatusCode::INTERNAL_SERVER_ERROR);
+        let mut response = HttpResponse::build(status);
+        response.content_type(PROBLEM_CONTENT_TYPE);
+        if let Some(seconds) = self.retry_after() {
+            response.append_header((header::RETRY_AFTER, seconds.to_string()));
+        }
+        response.body(serde_json::to_string(&self.problem(url)).unwrap_or_default())
+    }
+}
+
+impl From<FailureKind> for GetIconError {
+    fn from(kind: FailureKind) -> Self {
+        match kind {
+            FailureKind::Dns => GetIconError::Dns,
+            FailureKind::Timeout => GetIconError::Timeout,
+            FailureKind::Tls => GetIconError::Tls,
+            FailureKind::Connect => GetIconError::Connect,
+            FailureKind::NoIcons => GetIconError::NoIcons,
+            FailureKind::InvalidContent => GetIconError::NotAnImage(String::new()),
+        }
+    }
+}
+
+impl From<BlockedAddressError> for GetIconError {
+    fn from(err: BlockedAddressError) -> Self {
+        GetIconError::BlockedHost(err.host)
+    }
+}
+
+/// Finds the address guard's error in a request error, naming the host that was refused
+fn blocked_address(err: &reqwest::Error) -> Option<BlockedAddressError> {
+    let mut source = err.source();
+    while let Some(current) = source {
+        if let Some(blocked) = current.downcast_ref::<BlockedAddressError>() {
+            return Some(blocked.clone());
+        }
+        source = current.source();
+    }
+    None
+}
+
+impl fmt::Display for GetIconError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GetIconError::InvalidRequest(message) => write!(f, "{}", message),
+            GetIconError::InvalidUrl => write!(f, "Invalid URL"),
+            GetIconError::BlockedHost(host) => write!(f, "Destination address is not allowed: {}", host),
+            GetIconError::Dns => write!(f, "{}", FailureKind::Dns.message()),
+            GetIconError::Connect => write!(f, "{}", FailureKind::Connect.message()),
+            GetIconError::Timeout => write!(f, "{}", FailureKind::Timeout.message()),
+            GetIconError::Tls => write!(f, "{}", FailureKind::Tls.message()),
+            GetIconError::Interrupted => write!(f, "The site closed the connection before the response was complete"),
+            GetIconError::RedirectLoop => write!(f, "Too many redirects"),
+            GetIconError::InvalidRedirect(url) => write!(f, "Invalid redirect URL: {}", url),
+            GetIconError::HttpStatus(status) => write!(f, "Icon not found. Status: {}", status),
+            GetIconError::NotAnImage(detail) if detail.is_empty() => write!(f, "{}", FailureKind::InvalidContent.message()),
+            GetIconError::NotAnImage(detail) => write!(f, "{}: {}", FailureKind::InvalidContent.message(), detail),
+            GetIconError::Empty => write!(f, "Icon found but has zero size"),
+            GetIconError::TooLarge(bytes) => write!(f, "Icon is too large ({} bytes)", bytes),
+            GetIconError::NoIcons => write!(f, "{}", FailureKind::NoIcons.message()),
+            GetIconError::Backoff(kind, seconds) => write!(f, "{} (cached negative result, retrying in {}s)", kind.message(), seconds),
+            GetIconError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for GetIconError {}
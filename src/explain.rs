@@ -0,0 +1,249 @@
+#[cfg(feature = "server")]
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use url::Url;
+use crate::error::{Problem, GetIconError};
+use crate::models::{Icon, IconSource, ScoreBreakdown};
+#[cfg(feature = "server")]
+use crate::{
+    admin::{reject_unauthorized, AdminToken},
+    config::Config,
+    favicon::{find_best_icon_for_size, get_page_icons_with_config},
+    handlers::extract_headers_to_forward,
+    security::check_url_resolved,
+    url_utils::normalize_url,
+};
+#[cfg(feature = "server")]
+use log::{debug, warn};
+
+/// How discovery moved from one page to another
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectKind {
+    /// HTTP redirects, followed by the client (only the first and last URL are known)
+    Http,
+    /// A `<meta http-equiv="refresh">` tag in the page
+    MetaRefresh,
+}
+
+/// One redirect followed while fetching a site's page
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RedirectHop {
+    pub from: String,
+    pub to: String,
+    pub kind: RedirectKind,
+}
+
+/// Width and height of an icon, as declared by the page or measured from its content
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IconSize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl IconSize {
+    fn of(icon: &Icon) -> Self {
+        IconSize { width: icon.width, height: icon.height }
+    }
+}
+
+/// What validation made of a candidate
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ValidationOutcome {
+    /// The icon exists and is an image; ICO files list each embedded frame
+    Valid { frames: usize },
+    /// The icon was requested and rejected, with the error's code and message
+    Rejected { reason: String, message: String },
+    /// The icon was never requested, because better candidates were validated first
+    NotValidated,
+}
+
+/// Everything known about one candidate icon
+#[derive(Serialize, Debug, Clone)]
+pub struct CandidateReport {
+    pub url: String,
+    #[serde(rename = "type")]
+    pub content_type: String,
+    pub source: Option<IconSource>,
+    pub purpose: Option<String>,
+    pub declared_size: IconSize,
+    /// Size read from the image itself, if it was validated (the largest frame for ICO files)
+    pub actual_size: Option<IconSize>,
+    pub declared_score: ScoreBreakdown,
+    /// Score recalculated from the actual size, which decides the ranking of valid icons
+    pub actual_score: Option<ScoreBreakdown>,
+    pub validation: ValidationOutcome,
+}
+
+/// Why a site's icon was chosen: every candidate discovery considered and how it fared
+#[derive(Serialize, Debug, Clone)]
+pub struct Explanation {
+    pub url: String,
+    /// Page the candidates were collected from, after following redirects
+    pub final_url: String,
+    pub redirects: Vec<RedirectHop>,
+    /// Why the page itself couldn't be fetched; well-known paths are still tried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_error: Option<Problem>,
+    /// Why discovery found no usable icon, e.g. too many redirects
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Problem>,
+    pub candidates: Vec<CandidateReport>,
+    pub best_icon: Option<Icon>,
+}
+
+#[derive(Default)]
+struct TraceState {
+    final_url: Option<String>,
+    redirects: Vec<RedirectHop>,
+    page_error: Option<Problem>,
+    candidates: Vec<Icon>,
+    outcomes: HashMap<String, (ValidationOutcome, Option<Icon>)>,
+}
+
+/// Collects what discovery did for one site, for the /debug endpoint
+/// Passed to discovery and validation functions, which record into it as they go
+#[derive(Default)]
+pub struct DiscoveryTrace {
+    state: Mutex<TraceState>,
+}
+
+impl DiscoveryTrace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the page candidates are being collected from
+    pub fn page(&self, url: &Url) {
+        self.state.lock().unwrap().final_url = Some(url.to_string());
+    }
+
+    pub fn redirect(&self, from: &Url, to: &Url, kind: RedirectKind) {
+        self.state.lock().unwrap().redirects.push(RedirectHop {
+            from: from.to_string(),
+            to: to.to_string(),
+            kind,
+        });
+    }
+
+    /// Records why the page couldn't be fetched
+    pub fn page_error(&self, err: &GetIconError) {
+        self.state.lock().unwrap().page_error = Some(err.problem(None));
+    }
+
+    /// Records candidates in the order they were ranked
+    pub fn candidates(&self, icons: &[Icon]) {
+        self.state.lock().unwrap().candidates.extend(icons.iter().cloned());
+    }
+
+    /// Records a candidate that passed validation, with the icons it was measured as
+    pub fn validated(&self, icon: &Icon, measured: &[Icon]) {
+        let best = measured.iter().max_by_key(|icon| (icon.width.max(icon.height), icon.score)).cloned();
+        let outcome = ValidationOutcome::Valid { frames: measured.len() };
+        self.state.lock().unwrap().outcomes.insert(icon.url.clone(), (outcome, best));
+    }
+
+    pub fn rejected(&self, icon: &Icon, err: &GetIconError) {
+        let outcome = ValidationOutcome::Rejected { reason: err.code().to_string(), message: err.to_string() };
+        self.state.lock().unwrap().outcomes.insert(icon.url.clone(), (outcome, None));
+    }
+
+    /// Builds the report for a lookup of `url`
+    pub fn explain(
+        &self,
+        url: &Url,
+        error: Option<&GetIconError>,
+        best_icon: Option<Icon>
+    ) -> Explanation {
+        let state = self.state.lock().unwrap();
+        let candidates = state.candidates
+            .iter()
+            .map(|icon| {
+                let (validation, measured) = state.outcomes
+                    .get(&icon.url)
+                    .cloned()
+                    .unwrap_or((ValidationOutcome::NotValidated, None));
+                CandidateReport {
+                    url: icon.url.clone(),
+                    content_type: icon.content_type.clone(),
+                    source: icon.source,
+                    purpose: icon.purpose.clone(),
+                    declared_size: IconSize::of(icon),
+                    actual_size: measured.as_ref().map(IconSize::of),
+                    declared_score: icon.score_breakdown(),
+                    actual_score: measured.as_ref().map(Icon::score_breakdown),
+                    validation,
+                }
+            })
+            .collect();
+
+        Explanation {
+            url: url.to_string(),
+            final_url: state.final_url.clone().unwrap_or_else(|| url.to_string()),
+            redirects: state.redirects.clone(),
+            page_error: state.page_error.clone(),
+            error: error.map(|err| err.problem(Some(url))),
+            candidates,
+            best_icon,
+        }
+    }
+}
+
+/// Handler for /debug endpoint - explains how the icon for a URL is chosen
+/// Always runs a fresh discovery, bypassing every cache, and never stores the result
+#[cfg(feature = "server")]
+#[get("/debug")]
+pub async fn explain_discovery(
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+    token: Option<web::Data<AdminToken>>,
+    client: web::Data<reqwest::Client>,
+    config: web::Data<Config>
+) -> HttpResponse {
+    // Every call fetches the site and its candidates, so it is limited to admins
+    if let Some(response) = reject_unauthorized(&req, token.as_ref()) {
+        return response;
+    }
+    let url_str = match query.get("url") {
+        Some(u) => u,
+        None => return GetIconError::InvalidRequest("Missing url parameter".to_string()).error_response(None),
+    };
+    let requested_size = query.get("size").and_then(|s| s.parse::<u32>().ok());
+
+    let normalized_url = match normalize_url(url_str).await {
+        Some(u) => u,
+        None => return GetIconError::InvalidUrl.error_response(None),
+    };
+
+    // Refuse to fetch from private, loopback and link-local addresses
+    if let Err(err) = check_url_resolved(&normalized_url).await {
+        warn!("Blocked debug request for URL: {}", normalized_url);
+        return GetIconError::from(err).error_response(Some(&normalized_url));
+    }
+
+    debug!("Explaining icon discovery for URL: {}", normalized_url);
+    let forwarded_headers = extract_headers_to_forward(&req);
+    let discovery = &config.discovery;
+    let trace = DiscoveryTrace::new();
+    let discovered = get_page_icons_with_config(
+        client.as_ref(),
+        &normalized_url,
+        Some(&forwarded_headers),
+        None,
+        discovery,
+        Some(&trace)
+    ).await;
+
+    // Discovery returns validated icons, so pick one the same way /img and /json do
+    let best_icon = discovered
+        .as_ref()
+        .ok()
+        .and_then(|icons| find_best_icon_for_size(icons, requested_size).cloned());
+
+    HttpResponse::Ok()
+        .append_header(("Cache-Control", "no-store"))
+        .json(trace.explain(&normalized_url, discovered.as_ref().err(), best_icon))
+}
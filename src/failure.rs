@@ -0,0 +1,161 @@
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long failures are remembered, so repeated ones back off further (2 days)
+/// Longer than the longest backoff, so the attempt counter survives between retries
+pub const NEGATIVE_HISTORY_SECONDS: u64 = 172800;
+
+/// Why looking up a site's icon failed, stored in the negative cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// The site's name didn't resolve
+    Dns,
+    /// The site didn't answer in time
+    Timeout,
+    /// The TLS handshake failed
+    Tls,
+    /// The connection was refused or dropped
+    Connect,
+    /// The site has no usable icon
+    NoIcons,
+    /// The icon wasn't an image (HTML, empty or corrupt content)
+    InvalidContent,
+}
+
+impl FailureKind {
+    /// Classifies a failed request to an origin
+    /// Returns None for failures that aren't remembered: redirect problems, origin 5xx
+    /// responses, bodies cut off mid-transfer and requests that couldn't be built
+    pub fn from_request_error(err: &reqwest::Error) -> Option<Self> {
+        if err.is_timeout() {
+            Some(FailureKind::Timeout)
+        } else if err.is_connect() {
+            Some(classify_connect_error(err))
+        } else if err.is_status() {
+            err.status().filter(StatusCode::is_client_error).map(|_| FailureKind::NoIcons)
+        } else if is_interrupted_transfer(err) {
+            None
+        } else if err.is_decode() {
+            Some(FailureKind::InvalidContent)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureKind::Dns => "dns",
+            FailureKind::Timeout => "timeout",
+            FailureKind::Tls => "tls",
+            FailureKind::Connect => "connect",
+            FailureKind::NoIcons => "no_icons",
+            FailureKind::InvalidContent => "invalid_content",
+        }
+    }
+
+    /// Human readable reason, used in error responses
+    pub fn message(&self) -> &'static str {
+        match self {
+            FailureKind::Dns => "Site could not be resolved",
+            FailureKind::Timeout => "Site did not respond in time",
+            FailureKind::Tls => "TLS handshake with the site failed",
+            FailureKind::Connect => "Could not connect to the site",
+            FailureKind::NoIcons => "No valid icons found",
+            FailureKind::InvalidContent => "Icon content is not a valid image",
+        }
+    }
+
+    /// Status returned for this failure: 404 when the site has no icon, 502/504 when it couldn't be reached
+    pub fn status(&self) -> StatusCode {
+        match self {
+            FailureKind::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            FailureKind::Dns | FailureKind::Tls | FailureKind::Connect => StatusCode::BAD_GATEWAY,
+            FailureKind::NoIcons | FailureKind::InvalidContent => StatusCode::NOT_FOUND,
+        }
+    }
+
+    /// How long to wait before retrying after the first failure, and the most it grows to
+    /// Outages are retried soon; sites without an icon rarely get one within the hour
+    fn backoff_bounds(&self) -> (Duration, Duration) {
+        const MINUTE: u64 = 60;
+        const HOUR: u64 = 60 * MINUTE;
+        let (base, max) = match self {
+            FailureKind::Timeout | FailureKind::Connect => (MINUTE, HOUR),
+            FailureKind::Dns => (5 * MINUTE, 6 * HOUR),
+            FailureKind::Tls => (10 * MINUTE, 6 * HOUR),
+            FailureKind::NoIcons | FailureKind::InvalidContent => (HOUR, 24 * HOUR),
+        };
+        (Duration::from_secs(base), Duration::from_secs(max))
+    }
+
+    /// Retry delay after the given number of consecutive failures, doubling each time
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let (base, max) = self.backoff_bounds();
+        let factor = 1u32.checked_shl(attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+        base.saturating_mul(factor).min(max)
+    }
+}
+
+/// Checks if a response body stopped before it was complete
+/// reqwest reports body errors met while collecting or streaming a response as decode errors
+/// wrapping the body error, so the direct source is checked too
+pub(crate) fn is_interrupted_transfer(err: &reqwest::Error) -> bool {
+    err.is_body() || err.source().and_then(|source| source.downcast_ref::<reqwest::Error>()).is_some_and(reqwest::Error::is_body)
+}
+
+/// Tells DNS and TLS failures apart from other connection failures
+/// reqwest reports all of them as connect errors, so this looks at the whole error chain
+fn classify_connect_error(err: &reqwest::Error) -> FailureKind {
+    let mut chain = err.to_string();
+    let mut source = err.source();
+    while let Some(current) = source {
+        chain.push_str(": ");
+        chain.push_str(&current.to_string());
+        source = current.source();
+    }
+    let chain = chain.to_ascii_lowercase();
+
+    if chain.contains("dns error") || chain.contains("failed to lookup address") || chain.contains("name or service not known") {
+        FailureKind::Dns
+    } else if chain.contains("tls") || chain.contains("certificate") || chain.contains("handshake") {
+        FailureKind::Tls
+    } else {
+        FailureKind::Connect
+    }
+}
+
+/// A remembered failure: its kind, how many times in a row the lookup failed, and when to retry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NegativeEntry {
+    pub kind: FailureKind,
+    pub attempts: u32,
+    pub failed_at: Instant,
+    pub retry_after: Duration,
+}
+
+impl NegativeEntry {
+    /// Records a failure, continuing the attempt count of a previous failure that is still remembered
+    pub fn after(previous: Option<&NegativeEntry>, kind: FailureKind) -> Self {
+        let attempts = previous.map_or(1, |previous| previous.attempts.saturating_add(1));
+        NegativeEntry {
+            kind,
+            attempts,
+            failed_at: Instant::now(),
+            retry_after: kind.backoff(attempts),
+        }
+    }
+
+    /// Returns true while lookups should be answered from the negative cache
+    pub fn is_active(&self) -> bool {
+        self.failed_at.elapsed() < self.retry_after
+    }
+
+    /// Time left until the lookup is retried
+    pub fn remaining(&self) -> Duration {
+        self.retry_after.saturating_sub(self.failed_at.elapsed())
+    }
+}
@@ -0,0 +1,196 @@
+use ab_glyph::{Font, FontRef, OutlineCurve, Point};
+use bytes::Bytes;
+use image::{DynamicImage, RgbaImage};
+use std::fmt::Write;
+use url::Url;
+use crate::svg::parse_color;
+use crate::transform::{encode_image, OutputFormat, MAX_RESIZE_DIMENSION};
+use log::debug;
+
+/// Size of generated avatars when no size was requested
+pub const DEFAULT_AVATAR_SIZE: u32 = 64;
+
+/// Response header marking an image as generated rather than fetched
+pub const FALLBACK_HEADER: &str = "X-Icon-Fallback";
+
+/// Side length of the avatar's SVG coordinate system
+const VIEW_BOX: f32 = 100.0;
+
+/// Largest area the letter may cover, as (width, height) in view box units
+const LETTER_BOX: (f32, f32) = (60.0, 52.0);
+
+/// Background colors for generated avatars, all dark enough for white text
+const PALETTE: [[u8; 3]; 16] = [
+    [0xE5, 0x39, 0x35], [0xD8, 0x1B, 0x60], [0x8E, 0x24, 0xAA], [0x5E, 0x35, 0xB1],
+    [0x39, 0x49, 0xAB], [0x1E, 0x88, 0xE5], [0x03, 0x9B, 0xE5], [0x00, 0x83, 0x8F],
+    [0x00, 0x89, 0x7B], [0x43, 0xA0, 0x47], [0x55, 0x8B, 0x2F], [0xEF, 0x6C, 0x00],
+    [0xF4, 0x51, 0x1E], [0x6D, 0x4C, 0x41], [0x54, 0x6E, 0x7A], [0x37, 0x47, 0x4F],
+];
+
+/// What /img serves when no usable icon could be fetched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fallback {
+    /// Plain 404 response (the default)
+    NotFound,
+    /// Generated avatar showing the first letter of the domain
+    Letter,
+    /// Transparent PNG, 1x1 or the requested size
+    Blank,
+    /// 302 redirect to a caller-provided image
+    Redirect(Url),
+}
+
+impl Fallback {
+    /// Parses the `fallback` query parameter: `404`, `letter`, `blank` or an absolute http(s) URL
+    pub fn from_param(value: &str) -> Option<Self> {
+        let value = value.trim();
+        match value.to_ascii_lowercase().as_str() {
+            "404" => Some(Fallback::NotFound),
+            "letter" => Some(Fallback::Letter),
+            "blank" => Some(Fallback::Blank),
+            _ => Url::parse(value)
+                .ok()
+                .filter(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+                .map(Fallback::Redirect),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fallback::NotFound => "404",
+            Fallback::Letter => "letter",
+            Fallback::Blank => "blank",
+            Fallback::Redirect(_) => "redirect",
+        }
+    }
+}
+
+/// Encodes a fully transparent square PNG, 1x1 unless a size is given
+pub fn blank_png(size: Option<u32>) -> Option<Bytes> {
+    let size = size.unwrap_or(1).clamp(1, MAX_RESIZE_DIMENSION);
+    encode_image(&DynamicImage::ImageRgba8(RgbaImage::new(size, size)), OutputFormat::Png)
+}
+
+/// Checks if an Accept header explicitly lists SVG, so a vector avatar can be served
+pub fn accepts_svg(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        accept.split(',').any(|part| {
+            let mut pieces = part.split(';');
+            let media_type = pieces.next().unwrap_or("").trim();
+            let rejected = pieces
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .any(|q| q.trim().parse::<f32>().is_ok_and(|q| q <= 0.0));
+            media_type.eq_ignore_ascii_case("image/svg+xml") && !rejected
+        })
+    })
+}
+
+/// Picks the letter shown for a host, skipping a leading "www."
+pub fn avatar_letter(host: &str) -> char {
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    host.chars()
+        .find(|c| c.is_alphanumeric())
+        .map(|c| c.to_uppercase().next().unwrap_or(c))
+        .unwrap_or('?')
+}
+
+/// Picks the avatar background: the page's theme-color when it declares one,
+/// otherwise a palette color derived from a hash of the host
+pub fn avatar_background(host: &str, theme_color: Option<&str>) -> [u8; 3] {
+    if let Some(color) = theme_color.and_then(parse_color) {
+        return color;
+    }
+    let digest = md5::compute(host.to_ascii_lowercase().as_bytes());
+    PALETTE[digest[0] as usize % PALETTE.len()]
+}
+
+/// Chooses white or near-black text, whichever contrasts better with the background
+fn letter_color(background: [u8; 3]) -> [u8; 3] {
+    let [r, g, b] = background.map(|c| c as f32 / 255.0);
+    let luminance = 0.299 * r + 0.587 * g + 0.114 * b;
+    if luminance > 0.6 {
+        [0x21, 0x21, 0x21]
+    } else {
+        [0xFF, 0xFF, 0xFF]
+    }
+}
+
+fn hex(color: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+/// Renders a letter avatar for a host as a square SVG document
+/// The letter is drawn as a path from an embedded font, so the result
+/// looks the same everywhere and needs no fonts when rasterized
+pub fn letter_avatar_svg(host: &str, theme_color: Option<&str>, size: u32) -> String {
+    let letter = avatar_letter(host);
+    let background = avatar_background(host, theme_color);
+    debug!("Generating letter avatar '{}' for {} on {}", letter, host, hex(background));
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{size}" height="{size}" viewBox="0 0 {box} {box}"><rect width="{box}" height="{box}" fill="{fill}"/>"#,
+        size = size,
+        box = VIEW_BOX,
+        fill = hex(background),
+    );
+    if let Some(path) = letter_path(letter) {
+        let _ = write!(svg, r#"<path fill="{}" d="{}"/>"#, hex(letter_color(background)), path);
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Converts a glyph outline to SVG path data, centered in the view box
+fn letter_path(letter: char) -> Option<String> {
+    let font = FontRef::try_from_slice(notosans::BOLD_TTF).ok()?;
+    let glyph_id = font.glyph_id(letter);
+    if glyph_id.0 == 0 {
+        debug!("No glyph for avatar letter '{}'", letter);
+        return None;
+    }
+    let outline = font.outline(glyph_id)?;
+
+    // Font units have y pointing up; bounds.min holds the top-left corner
+    let (x_min, x_max) = (outline.bounds.min.x, outline.bounds.max.x);
+    let (y_max, y_min) = (outline.bounds.min.y, outline.bounds.max.y);
+    let scale = (LETTER_BOX.0 / (x_max - x_min)).min(LETTER_BOX.1 / (y_max - y_min));
+    let center_x = (x_min + x_max) / 2.0;
+    let center_y = (y_min + y_max) / 2.0;
+    let map = |p: Point| {
+        (
+            VIEW_BOX / 2.0 + (p.x - center_x) * scale,
+            VIEW_BOX / 2.0 - (p.y - center_y) * scale,
+        )
+    };
+
+    let mut path = String::new();
+    let mut last: Option<Point> = None;
+    for curve in &outline.curves {
+        let (start, end) = match curve {
+            OutlineCurve::Line(p0, p1) => (*p0, *p1),
+            OutlineCurve::Quad(p0, _, p2) => (*p0, *p2),
+            OutlineCurve::Cubic(p0, _, _, p3) => (*p0, *p3),
+        };
+        // A curve that doesn't continue from the previous one starts a new contour
+        if last != Some(start) {
+            let (x, y) = map(start);
+            let _ = write!(path, "M{:.2} {:.2}", x, y);
+        }
+        match curve {
+            OutlineCurve::Line(_, p1) => {
+                let (x, y) = map(*p1);
+                let _ = write!(path, "L{:.2} {:.2}", x, y);
+            },
+            OutlineCurve::Quad(_, p1, p2) => {
+                let ((x1, y1), (x, y)) = (map(*p1), map(*p2));
+                let _ = write!(path, "Q{:.2} {:.2} {:.2} {:.2}", x1, y1, x, y);
+            },
+            OutlineCurve::Cubic(_, p1, p2, p3) => {
+                let ((x1, y1), (x2, y2), (x, y)) = (map(*p1), map(*p2), map(*p3));
+                let _ = write!(path, "C{:.2} {:.2} {:.2} {:.2} {:.2} {:.2}", x1, y1, x2, y2, x, y);
+            },
+        }
+        last = Some(end);
+    }
+    Some(path)
+}
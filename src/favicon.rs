@@ -2,8 +2,14 @@ use reqwest;
 use scraper::{Html, Selector};
 use std::collections::{HashSet, HashMap};
 use url::Url;
-use crate::models::Icon;
+use crate::models::{Icon, IconSource};
 use crate::validation;
+use crate::security;
+use crate::error::GetIconError;
+use crate::config::DiscoveryConfig;
+use crate::metrics::metrics;
+use crate::explain::{DiscoveryTrace, RedirectKind};
+use std::time::Duration;
 // Duration not used, removing it
 use log::{info, warn, debug}; // Remove unused imports
 use futures::{stream, StreamExt};
@@ -13,17 +19,17 @@ use futures::{stream, StreamExt};
 pub fn select_user_agent_for_icon(icon: &Icon) -> &'static str {
     // Check for Apple icons
     if icon.url.contains("apple-touch-icon") || 
-       (icon.purpose.as_ref().map_or(false, |p| p.contains("apple-touch-icon"))) {
+       icon.purpose.as_ref().is_some_and(|p| p.contains("apple-touch-icon")) {
         // iOS/Safari User-Agent
         "Mozilla/5.0 (iPhone; CPU iPhone OS 18_1_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/18.1.1 Mobile/15E148 Safari/604.1"
     } 
     // Check for Android/maskable icons
-    else if icon.purpose.as_ref().map_or(false, |p| p.contains("maskable")) {
+    else if icon.purpose.as_ref().is_some_and(|p| p.contains("maskable")) {
         // Android/Chrome User-Agent
         "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/132.0.0.0 Mobile Safari/537.36"
     }
     // Check for Microsoft icons
-    else if icon.purpose.as_ref().map_or(false, |p| p.contains("msapplication")) {
+    else if icon.purpose.as_ref().is_some_and(|p| p.contains("msapplication")) {
         // Windows/Chrome User-Agent
         "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/132.0.0.0 Safari/537.36"
     }
@@ -34,14 +40,16 @@ pub fn select_user_agent_for_icon(icon: &Icon) -> &'static str {
 }
 
 // Use the validation functions from the validation module
-use crate::validation::validate_icon;
+use crate::validation::validate_and_measure_icon;
 
 /// Try additional common icon locations that might not be explicitly referenced
 /// Now uses parallel processing for significant performance improvement
 async fn try_additional_icon_sources(
     client: &reqwest::Client,
     url: &Url,
-    forwarded_headers: Option<&HashMap<String, String>>
+    forwarded_headers: Option<&HashMap<String, String>>,
+    max_concurrent: usize,
+    trace: Option<&DiscoveryTrace>
 ) -> Vec<Icon> {
     debug!("Trying additional icon sources in parallel for URL: {}", url);
     
@@ -93,7 +101,7 @@ async fn try_additional_icon_sources(
             
             // Determine content type and size from path
             let (content_type, width, height) = if path.ends_with(".png") {
-                let size = path.split('-').last()
+                let size = path.split('-').next_back()
                     .and_then(|s| s.split('.').next())
                     .and_then(|s| s.split('x').next())
                     .and_then(|s| s.parse::<u32>().ok());
@@ -113,14 +121,18 @@ async fn try_additional_icon_sources(
                 content_type,
                 width,
                 height,
-            );
+            ).with_source(IconSource::WellKnown);
             
             potential_icons.push(icon);
         }
     }
     
-    // Define the maximum number of concurrent validation tasks
-    const MAX_CONCURRENT_VALIDATIONS: usize = 5;
+    if let Some(trace) = trace {
+        for icon in &mut potential_icons {
+            icon.calculate_score();
+        }
+        trace.candidates(&potential_icons);
+    }
     
     // Validate all possible icons in parallel
     debug!("Validating {} potential additional icons in parallel", potential_icons.len());
@@ -132,20 +144,21 @@ async fn try_additional_icon_sources(
             
             async move {
                 debug!("Trying additional icon path: {}", icon.url);
-                if validate_icon(&client, &icon, headers_opt.as_ref()).await {
-                    debug!("Additional icon validated successfully: {}", icon.url);
-                    Some(icon)
+                let icon_url = icon.url.clone();
+                let validated = validate_and_measure_icon(&client, icon, headers_opt.as_ref(), trace).await;
+                if !validated.is_empty() {
+                    debug!("Additional icon validated successfully: {}", icon_url);
                 } else {
-                    debug!("Additional icon validation failed: {}", icon.url);
-                    None
+                    debug!("Additional icon validation failed: {}", icon_url);
                 }
+                validated
             }
         })
-        .buffer_unordered(MAX_CONCURRENT_VALIDATIONS)
+        .buffer_unordered(max_concurrent.max(1))
         .collect::<Vec<_>>()
         .await
         .into_iter()
-        .filter_map(|x| x)
+        .flatten()
         .collect();
     
     debug!("Found {} valid additional icons", additional_icons.len());
@@ -153,14 +166,28 @@ async fn try_additional_icon_sources(
 }
 
 /// Gets all available icons from a webpage with enhanced detection and validation
+/// Uses the default discovery limits
 pub async fn get_page_icons(
     client: &reqwest::Client, 
     url: &Url,
     forwarded_headers: Option<&HashMap<String, String>>,
     redirect_count: Option<u8> // New parameter for tracking redirects
-) -> Vec<Icon> {
-    const MAX_REDIRECTS: u8 = 5;
-    
+) -> Result<Vec<Icon>, GetIconError> {
+    get_page_icons_with_config(client, url, forwarded_headers, redirect_count, &DiscoveryConfig::default(), None).await
+}
+
+/// Like `get_page_icons` with the given discovery limits
+/// Returns the validated icons, or why none were found: the page's error when it couldn't be
+/// fetched, so callers can tell an unreachable site from one without icons
+/// Redirects, candidates and validation results are recorded in `trace`, if given
+pub async fn get_page_icons_with_config(
+    client: &reqwest::Client, 
+    url: &Url,
+    forwarded_headers: Option<&HashMap<String, String>>,
+    redirect_count: Option<u8>,
+    discovery: &DiscoveryConfig,
+    trace: Option<&DiscoveryTrace>
+) -> Result<Vec<Icon>, GetIconError> {
     // Use a mutable URL that we can update if we encounter redirects
     let mut current_url = url.clone();
     let mut current_redirect_count = redirect_count.unwrap_or(0);
@@ -168,37 +195,19 @@ pub async fn get_page_icons(
     // Loop to handle meta refresh redirects
     loop {
         // Check if we've exceeded the redirect limit
-        if current_redirect_count > MAX_REDIRECTS {
+        if current_redirect_count > discovery.max_redirects {
             warn!("Too many meta refresh redirects for URL: {}", current_url);
-            return Vec::new();
+            return Err(GetIconError::RedirectLoop);
         }
         
-        info!("Fetching icons for URL: {} (redirect count: {})", current_url, current_redirect_count);
-        let mut icons = HashSet::new();
-        let mut validated_icons: Vec<Icon> = Vec::new();
-        
-        // Try direct favicon.ico
-        let favicon_url = current_url.join("/favicon.ico").ok();
-        if let Some(favicon_url) = favicon_url {
-            icons.insert(Icon::new(
-                favicon_url.to_string(),
-                "image/x-icon".to_string(),
-                Some(16),
-                Some(16),
-            ));
+        // Refuse to fetch internal addresses (meta refresh hops can point anywhere)
+        if let Err(err) = security::check_url(&current_url) {
+            warn!("Refusing to fetch icons from blocked address: {}", current_url);
+            return Err(err.into());
         }
         
-        // Try apple-touch-icon.png and apple-touch-icon-precomposed.png
-        for apple_icon in &["/apple-touch-icon.png", "/apple-touch-icon-precomposed.png"] {
-            if let Ok(apple_url) = current_url.join(apple_icon) {
-                icons.insert(Icon::new(
-                    apple_url.to_string(),
-                    "image/png".to_string(),
-                    Some(180),
-                    Some(180),
-                ).with_purpose(Some("apple-touch-icon".to_string())));
-            }
-        }
+        info!("Fetching icons for URL: {} (redirect count: {})", current_url, current_redirect_count);
+        let mut icons = HashSet::new();
         
         let mut manifest_urls = Vec::new();
         
@@ -221,8 +230,29 @@ pub async fn get_page_icons(
             request_builder = request_builder.header(name, value);
         }
         
-        if let Ok(response) = request_builder.send().await {
+        let mut page_error = None;
+        let page_response = match request_builder.send().await {
+            Ok(response) => {
+                let outcome = if response.status().is_success() { "ok" } else { "http_error" };
+                metrics().record_upstream_fetch("page", outcome);
+                Some(response)
+            },
+            Err(err) => {
+                debug!("Failed to fetch HTML from URL: {}: {}", current_url, err);
+                let err = GetIconError::from_request_error(&err);
+                metrics().record_upstream_fetch("page", err.code());
+                if let Some(trace) = trace {
+                    trace.page_error(&err);
+                }
+                page_error = Some(err);
+                None
+            }
+        };
+        if let Some(response) = page_response {
             // Update current_url to the final URL after following any HTTP redirects
+            if let Some(trace) = trace.filter(|_| response.url() != &current_url) {
+                trace.redirect(&current_url, response.url(), RedirectKind::Http);
+            }
             current_url = response.url().clone();
             debug!("Successfully fetched HTML from URL: {}, status: {}", current_url, response.status());
             if let Ok(text) = response.text().await {
@@ -238,10 +268,13 @@ pub async fn get_page_icons(
                         relative_url
                     } else {
                         warn!("Failed to parse redirect URL: {}", redirect_url);
-                        return Vec::new();
+                        return Err(GetIconError::InvalidRedirect(redirect_url));
                     };
                     
                     // Update the current URL and increment the redirect counter
+                    if let Some(trace) = trace {
+                        trace.redirect(&current_url, &parsed_redirect_url, RedirectKind::MetaRefresh);
+                    }
                     current_url = parsed_redirect_url;
                     current_redirect_count += 1;
                     
@@ -292,8 +325,11 @@ pub async fn get_page_icons(
                                     .unwrap_or((None, None));
                                 
                                 // Get purpose from rel attribute
-                                let purpose = if let Some(rel) = element.value().attr("rel") {
-                                    Some(rel.to_string())
+                                let purpose = element.value().attr("rel").map(|rel| rel.to_string());
+                                
+                                // Pinned-tab mask icons declare the color they should be filled with
+                                let color = if purpose.as_ref().is_some_and(|p| p.contains("mask-icon")) {
+                                    element.value().attr("color").map(|c| c.to_string())
                                 } else {
                                     None
                                 };
@@ -303,7 +339,7 @@ pub async fn get_page_icons(
                                     content_type,
                                     width,
                                     height,
-                                ).with_purpose(purpose));
+                                ).with_purpose(purpose).with_color(color).with_source(IconSource::Html));
                             }
                         }
                     } else if tag_name == "meta" && element.value().attr("name") == Some("msapplication-TileImage") {
@@ -315,7 +351,7 @@ pub async fn get_page_icons(
                                     "image/png".to_string(),
                                     Some(144),
                                     Some(144),
-                                ).with_purpose(Some("msapplication-TileImage".to_string())));
+                                ).with_purpose(Some("msapplication-TileImage".to_string())).with_source(IconSource::Html));
                             }
                         }
                     }
@@ -336,8 +372,14 @@ pub async fn get_page_icons(
                 for element in document.select(&browserconfig_selector) {
                     if let Some(content) = element.value().attr("content") {
                         if let Ok(config_url) = current_url.join(content) {
+                            if security::check_url(&config_url).is_err() {
+                                continue;
+                            }
+                            
                             // Try to fetch browserconfig.xml
-                            if let Ok(config_response) = client.get(config_url).send().await {
+                            let config_result = client.get(config_url).send().await;
+                            record_fetch("browserconfig", &config_result);
+                            if let Ok(config_response) = config_result {
                                 if let Ok(config_text) = config_response.text().await {
                                     // Very basic parsing of browserconfig.xml
                                     if let Some(tile_image) = config_text.lines()
@@ -353,7 +395,7 @@ pub async fn get_page_icons(
                                                 "image/png".to_string(),
                                                 Some(144),
                                                 Some(144),
-                                            ).with_purpose(Some("msapplication-tile".to_string())));
+                                            ).with_purpose(Some("msapplication-tile".to_string())).with_source(IconSource::Browserconfig));
                                         }
                                     }
                                 }
@@ -372,7 +414,7 @@ pub async fn get_page_icons(
                                 "image/jpeg".to_string(), // Assume JPEG, will be corrected if needed
                                 None,
                                 None,
-                            ).with_purpose(Some("og:image".to_string())));
+                            ).with_purpose(Some("og:image".to_string())).with_source(IconSource::OgImage));
                         }
                     }
                 }
@@ -391,6 +433,10 @@ pub async fn get_page_icons(
         
         // Process manifest files
         for manifest_url in &manifest_urls {
+            if security::check_url(manifest_url).is_err() {
+                continue;
+            }
+            
             debug!("Fetching web app manifest from URL: {}", manifest_url);
             
             // Create a copy of forwarded headers that we can modify
@@ -410,7 +456,9 @@ pub async fn get_page_icons(
                 manifest_req = manifest_req.header(name, value);
             }
             
-            if let Ok(manifest_response) = manifest_req.send().await {
+            let manifest_result = manifest_req.send().await;
+            record_fetch("manifest", &manifest_result);
+            if let Ok(manifest_response) = manifest_result {
                 debug!("Successfully fetched manifest from URL: {}, status: {}", manifest_url, manifest_response.status());
                 if let Ok(manifest_text) = manifest_response.text().await {
                     if let Ok(manifest) = serde_json::from_str::<serde_json::Value>(&manifest_text) {
@@ -459,7 +507,7 @@ pub async fn get_page_icons(
                                             content_type,
                                             width,
                                             height,
-                                        ).with_purpose(purpose));
+                                        ).with_purpose(purpose).with_source(IconSource::Manifest));
                                     }
                                 }
                             }
@@ -469,6 +517,30 @@ pub async fn get_page_icons(
             }
         }
         
+        // Try direct favicon.ico
+        // Conventional paths go in last, so an icon the page declares at the same URL keeps its metadata
+        let favicon_url = current_url.join("/favicon.ico").ok();
+        if let Some(favicon_url) = favicon_url {
+            icons.insert(Icon::new(
+                favicon_url.to_string(),
+                "image/x-icon".to_string(),
+                Some(16),
+                Some(16),
+            ).with_source(IconSource::WellKnown));
+        }
+        
+        // Try apple-touch-icon.png and apple-touch-icon-precomposed.png
+        for apple_icon in &["/apple-touch-icon.png", "/apple-touch-icon-precomposed.png"] {
+            if let Ok(apple_url) = current_url.join(apple_icon) {
+                icons.insert(Icon::new(
+                    apple_url.to_string(),
+                    "image/png".to_string(),
+                    Some(180),
+                    Some(180),
+                ).with_purpose(Some("apple-touch-icon".to_string())).with_source(IconSource::WellKnown));
+            }
+        }
+        
         // Validate all collected icons
         let mut icon_vec: Vec<Icon> = icons.into_iter().collect();
         
@@ -478,35 +550,40 @@ pub async fn get_page_icons(
         }
         
         // Sort by score (highest first)
-        icon_vec.sort_by(|a, b| b.score.cmp(&a.score));
+        icon_vec.sort_by_key(|icon| std::cmp::Reverse(icon.score));
+        if let Some(trace) = trace {
+            trace.page(&current_url);
+            trace.candidates(&icon_vec);
+        }
         
-        // Validate the top icons (up to 5) in parallel to avoid excessive sequential requests
-        debug!("Validating top {} icons in parallel from URL: {}", icon_vec.len().min(5), current_url);
-        let icons_to_validate: Vec<Icon> = icon_vec.iter().take(5).cloned().collect();
+        // Validate the top icons (up to the cutoff) in parallel to avoid excessive sequential requests
+        let cutoff = discovery.validation_cutoff;
+        debug!("Validating top {} icons in parallel from URL: {}", icon_vec.len().min(cutoff), current_url);
+        let icons_to_validate: Vec<Icon> = icon_vec.iter().take(cutoff).cloned().collect();
 
         // Use the parallel validate_icons function for significant performance improvement
         let headers = forwarded_headers
             .cloned()
             .unwrap_or_else(HashMap::new);
 
-        validated_icons = validation::validate_icons(client, &icons_to_validate, &headers).await;
+        let mut validated_icons = validation::validate_icons(client, &icons_to_validate, &headers, discovery.max_concurrent_validations, trace).await;
         
         // If we found valid icons, return them
         if !validated_icons.is_empty() {
             // Sort validated icons by score
-            validated_icons.sort_by(|a, b| b.score.cmp(&a.score));
+            validated_icons.sort_by_key(|icon| std::cmp::Reverse(icon.score));
             info!("Found {} valid icons for URL: {}", validated_icons.len(), current_url);
             debug!("Best icon: {} (type: {}, size: {}x{})", 
                 validated_icons[0].url, 
                 validated_icons[0].content_type,
                 validated_icons[0].width.unwrap_or(0),
                 validated_icons[0].height.unwrap_or(0));
-            return validated_icons;
+            return Ok(validated_icons);
         }
         
         // If no valid icons found, try additional sources
         debug!("No valid icons found in primary sources, trying additional sources for URL: {}", current_url);
-        let additional_icons = try_additional_icon_sources(client, &current_url, forwarded_headers).await;
+        let additional_icons = try_additional_icon_sources(client, &current_url, forwarded_headers, discovery.max_concurrent_validations, trace).await;
         if !additional_icons.is_empty() {
             let mut result = additional_icons;
             // Calculate scores for additional icons
@@ -514,23 +591,65 @@ pub async fn get_page_icons(
                 icon.calculate_score();
             }
             // Sort by score
-            result.sort_by(|a, b| b.score.cmp(&a.score));
+            result.sort_by_key(|icon| std::cmp::Reverse(icon.score));
             info!("Found {} valid icons from additional sources for URL: {}", result.len(), current_url);
             debug!("Best additional icon: {} (type: {}, size: {}x{})", 
                 result[0].url, 
                 result[0].content_type,
                 result[0].width.unwrap_or(0),
                 result[0].height.unwrap_or(0));
-            return result;
+            return Ok(result);
         }
         
-        // If still no icons found, return the original list (which might have invalid icons)
-        // This allows the handler to attempt to fetch them anyway as a last resort
-        warn!("No valid icons found for URL: {}, returning unvalidated icons as last resort", current_url);
-        return icon_vec;
+        // Nothing validated: blame the page if it couldn't be fetched
+        warn!("No valid icons found for URL: {}", current_url);
+        return Err(page_error.unwrap_or(GetIconError::NoIcons));
     }
 }
 
+/// Counts a request to a website under `target`, by its status or error code
+pub(crate) fn record_fetch(target: &str, result: &Result<reqwest::Response, reqwest::Error>) {
+    let outcome = match result {
+        Ok(response) if response.status().is_success() => "ok",
+        Ok(_) => "http_error",
+        Err(err) => GetIconError::from_request_error(err).code(),
+    };
+    metrics().record_upstream_fetch(target, outcome);
+}
+
+/// Fetches a page and returns the color declared by its theme-color meta tag
+/// Used to color generated fallback avatars; any failure, including a timeout
+/// after `timeout` (usually much shorter than discovery), simply yields None
+pub async fn fetch_theme_color(
+    client: &reqwest::Client,
+    url: &Url,
+    forwarded_headers: Option<&HashMap<String, String>>,
+    timeout: Duration
+) -> Option<String> {
+    if security::check_url(url).is_err() {
+        return None;
+    }
+
+    let mut request_builder = client.get(url.as_str()).timeout(timeout);
+    if let Some(headers) = forwarded_headers {
+        for (name, value) in headers {
+            request_builder = request_builder.header(name, value);
+        }
+    }
+
+    let result = request_builder.send().await;
+    record_fetch("theme_color", &result);
+    let response = result.ok()?;
+    if !response.status().is_success() {
+        debug!("Theme color lookup for {} failed with status {}", url, response.status());
+        return None;
+    }
+    let text = response.text().await.ok()?;
+    let color = validation::extract_theme_color(text.as_bytes());
+    debug!("Theme color for {}: {:?}", url, color);
+    color
+}
+
 /// Finds the best icon for a specific size requirement
 pub fn find_best_icon_for_size(icons: &[Icon], requested_size: Option<u32>) -> Option<&Icon> {
     if icons.is_empty() {
@@ -543,11 +662,7 @@ pub fn find_best_icon_for_size(icons: &[Icon], requested_size: Option<u32>) -> O
             .filter(|icon| icon.width.is_some() && icon.height.is_some())
             .min_by_key(|icon| {
                 let icon_size = icon.width.unwrap_or(0).max(icon.height.unwrap_or(0));
-                if icon_size >= size {
-                    icon_size - size // Prefer slightly larger than smaller
-                } else {
-                    size - icon_size
-                }
+                icon_size.abs_diff(size)
             })
             .or(Some(&icons[0])) // Fallback to highest scored icon
     } else {
@@ -0,0 +1,389 @@
+use bytes::Bytes;
+use reqwest::{header, StatusCode};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+use crate::cache::{IconCache, UpstreamValidators};
+use crate::config::{DiscoveryConfig, HttpConfig};
+use crate::error::GetIconError;
+use crate::favicon::{find_best_icon_for_size, get_page_icons_with_config, select_user_agent_for_icon};
+use crate::metrics::metrics;
+use crate::models::Icon;
+use crate::security::{check_url, check_url_resolved, guard_client_builder};
+use crate::transform::{spawn_transform, ImageOptions};
+use crate::url_utils::normalize_url;
+use crate::validation::{is_html_content, validate_image_content, MAX_ICON_BYTES};
+use log::{debug, warn};
+
+/// Ranks validated icons for `IconFetcher`; the highest score is preferred
+/// Implemented for closures, e.g. `|icon: &Icon| icon.width.unwrap_or(0)`
+pub trait IconScorer: Send + Sync {
+    fn score(&self, icon: &Icon) -> u32;
+}
+
+impl<F> IconScorer for F
+where
+    F: Fn(&Icon) -> u32 + Send + Sync,
+{
+    fn score(&self, icon: &Icon) -> u32 {
+        self(icon)
+    }
+}
+
+/// Result of downloading an icon with `download_icon`
+#[derive(Debug, Clone)]
+pub enum Download {
+    /// The origin confirmed that the copy described by the given validators is current
+    NotModified,
+    /// The icon's content, with the validators to revalidate it later
+    Fetched {
+        content: Bytes,
+        upstream: Option<UpstreamValidators>,
+    },
+}
+
+/// A site's validated icons, best first
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    /// The normalized URL that was looked up
+    pub url: Url,
+    pub icons: Vec<Icon>,
+}
+
+impl Discovery {
+    /// The icon closest to `size`, or the best-scoring one without a size
+    pub fn best(&self, size: Option<u32>) -> Option<&Icon> {
+        find_best_icon_for_size(&self.icons, size)
+    }
+}
+
+/// An icon downloaded, checked and transformed by `IconFetcher`
+#[derive(Debug, Clone)]
+pub struct FetchedIcon {
+    /// The normalized URL that was looked up
+    pub url: Url,
+    /// The icon that was chosen
+    pub icon: Icon,
+    pub content: Bytes,
+    pub content_type: String,
+}
+
+/// Discovers, validates and downloads icons, without an HTTP server
+/// Built with `IconFetcher::builder()`; cheap to clone and share between tasks
+#[derive(Clone)]
+pub struct IconFetcher {
+    client: reqwest::Client,
+    cache: Option<Arc<IconCache>>,
+    discovery: DiscoveryConfig,
+    headers: HashMap<String, String>,
+    max_icon_bytes: u64,
+    scorer: Option<Arc<dyn IconScorer>>,
+}
+
+/// Settings for an `IconFetcher`
+pub struct IconFetcherBuilder {
+    client: Option<reqwest::Client>,
+    timeout: Duration,
+    connect_timeout: Option<Duration>,
+    cache: Option<Arc<IconCache>>,
+    discovery: DiscoveryConfig,
+    headers: HashMap<String, String>,
+    max_icon_bytes: u64,
+    scorer: Option<Arc<dyn IconScorer>>,
+}
+
+impl Default for IconFetcherBuilder {
+    fn default() -> Self {
+        IconFetcherBuilder {
+            client: None,
+            timeout: Duration::from_secs(HttpConfig::default().timeout_seconds),
+            connect_timeout: None,
+            cache: None,
+            discovery: DiscoveryConfig::default(),
+            headers: HashMap::new(),
+            max_icon_bytes: MAX_ICON_BYTES,
+            scorer: None,
+        }
+    }
+}
+
+impl IconFetcherBuilder {
+    /// Uses an existing client instead of building one
+    /// The timeouts below are then ignored, and the client should be built with
+    /// `guard_client_builder` to keep private addresses out of reach
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Total time allowed for each request (10 seconds by default)
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Time allowed for establishing each connection (only the total timeout applies by default)
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Shares discovered icons through a cache, e.g. the server's
+    pub fn with_cache(mut self, cache: Arc<IconCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Limits on redirects, validated candidates and concurrent validations
+    pub fn with_discovery(mut self, discovery: DiscoveryConfig) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Largest icon downloaded by `fetch` and `fetch_best` (`MAX_ICON_BYTES` by default)
+    pub fn with_max_icon_bytes(mut self, max_icon_bytes: u64) -> Self {
+        self.max_icon_bytes = max_icon_bytes;
+        self
+    }
+
+    /// Adds a header to every request, e.g. `Accept-Language`
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Replaces the built-in scoring used to rank validated icons
+    pub fn with_scorer(mut self, scorer: impl IconScorer + 'static) -> Self {
+        self.scorer = Some(Arc::new(scorer));
+        self
+    }
+
+    pub fn build(self) -> Result<IconFetcher, reqwest::Error> {
+        let client = match self.client {
+            Some(client) => client,
+            None => {
+                // Same settings as the server: many sites serve icons with broken certificates
+                let mut builder = reqwest::Client::builder()
+                    .danger_accept_invalid_certs(true)
+                    .timeout(self.timeout);
+                if let Some(connect_timeout) = self.connect_timeout {
+                    builder = builder.connect_timeout(connect_timeout);
+                }
+                guard_client_builder(builder).build()?
+            }
+        };
+        Ok(IconFetcher {
+            client,
+            cache: self.cache,
+            discovery: self.discovery,
+            headers: self.headers,
+            max_icon_bytes: self.max_icon_bytes,
+            scorer: self.scorer,
+        })
+    }
+}
+
+impl IconFetcher {
+    pub fn builder() -> IconFetcherBuilder {
+        IconFetcherBuilder::default()
+    }
+
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Finds and validates the icons of a site, e.g. `example.com`
+    pub async fn discover(&self, url: &str) -> Result<Discovery, GetIconError> {
+        let normalized_url = normalize_url(url).await.ok_or(GetIconError::InvalidUrl)?;
+        check_url_resolved(&normalized_url).await?;
+
+        let icons = discover_icons(&self.client, self.cache.as_deref(), &self.discovery, &normalized_url, &self.headers).await?;
+        let mut icons = icons.to_vec();
+        if let Some(scorer) = &self.scorer {
+            for icon in &mut icons {
+                icon.score = scorer.score(icon);
+            }
+            icons.sort_by_key(|icon| Reverse(icon.score));
+        }
+        Ok(Discovery { url: normalized_url, icons })
+    }
+
+    /// Downloads the icon closest to `size` (the best-scoring one without a size)
+    /// Frames of multi-resolution ICO files are returned as PNG
+    pub async fn fetch_best(&self, url: &str, size: Option<u32>) -> Result<FetchedIcon, GetIconError> {
+        self.fetch(url, &ImageOptions::new(size, false)).await
+    }
+
+    /// Downloads the best icon for `options.size` and resizes or converts it as requested
+    pub async fn fetch(&self, url: &str, options: &ImageOptions) -> Result<FetchedIcon, GetIconError> {
+        let discovery = self.discover(url).await?;
+        let icon = discovery.best(options.size).cloned().ok_or(GetIconError::NoIcons)?;
+        check_icon_url(&icon)?;
+
+        let content = match download_icon(&self.client, &icon, &self.headers, None, self.max_icon_bytes).await? {
+            Download::Fetched { content, .. } => content,
+            Download::NotModified => return Err(GetIconError::HttpStatus(StatusCode::NOT_MODIFIED.as_u16())),
+        };
+        let (content, content_type) = spawn_transform(&icon, content, options).await;
+        Ok(FetchedIcon { url: discovery.url, icon, content, content_type })
+    }
+}
+
+/// Returns the validated icons of a site, best first
+/// With a cache, every size, format and caller for the same site shares one discovery
+pub async fn discover_icons(
+    client: &reqwest::Client,
+    cache: Option<&IconCache>,
+    discovery: &DiscoveryConfig,
+    normalized_url: &Url,
+    forwarded_headers: &HashMap<String, String>
+) -> Result<Arc<Vec<Icon>>, GetIconError> {
+    // Discovery only returns icons that passed validation, sorted by score
+    let discover = async {
+        get_page_icons_with_config(client, normalized_url, Some(forwarded_headers), None, discovery, None)
+            .await
+            .inspect_err(|err| warn!("Failed to find icons for URL: {}: {}", normalized_url, err))
+    };
+
+    match cache {
+        Some(cache) => cache.get_or_discover(normalized_url.as_str(), discover)
+            .await
+            .map_err(|err| err.as_ref().clone()),
+        None => discover.await.map(Arc::new),
+    }
+}
+
+/// Icon URLs come from the page itself, so they need the same address check as the page
+pub fn check_icon_url(icon: &Icon) -> Result<(), GetIconError> {
+    let url = Url::parse(&icon.url).map_err(|_| GetIconError::InvalidUrl)?;
+    if let Err(err) = check_url(&url) {
+        warn!("Blocked icon URL: {}", icon.url);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Downloads an icon and checks that it is a real image of at most `max_bytes`
+/// With `revalidate`, the origin is asked whether that copy is still current and may answer
+/// `Download::NotModified`
+pub async fn download_icon(
+    client: &reqwest::Client,
+    icon: &Icon,
+    forwarded_headers: &HashMap<String, String>,
+    revalidate: Option<&UpstreamValidators>,
+    max_bytes: u64
+) -> Result<Download, GetIconError> {
+    // Fetch the icon with the User-Agent that suits its type, replacing the forwarded one
+    let mut headers = forwarded_headers.clone();
+    headers.insert("User-Agent".to_string(), select_user_agent_for_icon(icon).to_string());
+    let mut request_builder = client.get(&icon.url);
+    for (name, value) in &headers {
+        request_builder = request_builder.header(name, value);
+    }
+
+    // Ask the origin whether the icon we already have is still current
+    if let Some(upstream) = revalidate {
+        if let Some(etag) = &upstream.etag {
+            request_builder = request_builder.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &upstream.last_modified {
+            request_builder = request_builder.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let mut response = match request_builder.send().await {
+        Ok(response) => {
+            let outcome = match response.status() {
+                StatusCode::NOT_MODIFIED => "not_modified",
+                status if status.is_success() => "ok",
+                _ => "http_error",
+            };
+            metrics().record_upstream_fetch("icon", outcome);
+            response
+        },
+        Err(err) => {
+            let error = GetIconError::from_request_error(&err);
+            metrics().record_upstream_fetch("icon", error.code());
+            warn!("Failed to fetch icon {}: {} ({})", icon.url, err, error.code());
+            return Err(error);
+        }
+    };
+
+    // Unchanged at the origin: the caller keeps its copy
+    if revalidate.is_some() && response.status() == StatusCode::NOT_MODIFIED {
+        debug!("Icon not modified at origin: {}", icon.url);
+        return Ok(Download::NotModified);
+    }
+
+    // Reject redirects to non-image resources (like a login page) and non-image content types
+    let content_type = response.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    if let Some(content_type) = content_type.filter(|content_type| !content_type.starts_with("image/")) {
+        let final_url = response.url().to_string();
+        if final_url != icon.url {
+            warn!("Icon redirected to non-image resource: {} -> {} (Content-Type: {})", icon.url, final_url, content_type);
+            return Err(GetIconError::NotAnImage(format!("redirected to {}", final_url)));
+        }
+        warn!("Invalid content type for icon: {} (Content-Type: {})", icon.url, content_type);
+        return Err(GetIconError::NotAnImage(content_type));
+    }
+
+    if !response.status().is_success() {
+        warn!("Icon not found: {} (Status: {})", icon.url, response.status());
+        return Err(GetIconError::HttpStatus(response.status().as_u16()));
+    }
+
+    // Refuse icons that announce a size over the limit before downloading them
+    if let Some(length) = response.content_length().filter(|length| *length > max_bytes) {
+        warn!("Icon too large: {} ({} bytes)", icon.url, length);
+        return Err(GetIconError::TooLarge(length));
+    }
+
+    // Read the body chunk by chunk, so a body without (or with a false) Content-Length
+    // is dropped as soon as it goes over the limit
+    let upstream = UpstreamValidators::from_headers(&icon.url, response.headers());
+    let mut content = Vec::new();
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                let length = (content.len() + chunk.len()) as u64;
+                if length > max_bytes {
+                    warn!("Icon too large: {} (over {} bytes)", icon.url, length);
+                    return Err(GetIconError::TooLarge(length));
+                }
+                content.extend_from_slice(&chunk);
+            },
+            Ok(None) => break,
+            Err(err) => {
+                warn!("Failed to read icon content: {}: {}", icon.url, err);
+                return Err(GetIconError::from_request_error(&err));
+            }
+        }
+    }
+    let content = Bytes::from(content);
+
+    if content.is_empty() {
+        warn!("Zero-size icon: {}", icon.url);
+        return Err(GetIconError::Empty);
+    }
+
+    // Check for HTML content disguised as an image
+    if is_html_content(&content) {
+        warn!("HTML content disguised as an image: {}", icon.url);
+        return Err(GetIconError::NotAnImage("content is HTML".to_string()));
+    }
+    if !validate_image_content(&content, &icon.content_type) {
+        warn!("Invalid image content: {}", icon.url);
+        let detail = match image::guess_format(&content) {
+            Ok(format) => format!("{} content could not be decoded", format.to_mime_type()),
+            Err(_) => "content does not match any known image signature".to_string(),
+        };
+        return Err(GetIconError::NotAnImage(detail));
+    }
+
+    Ok(Download::Fetched { content, upstream })
+}
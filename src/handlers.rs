@@ -1,15 +1,24 @@
-use actix_web::{get, web, HttpResponse, HttpRequest, http::header};
+use actix_web::{get, post, web, HttpResponse, HttpResponseBuilder, HttpRequest, http::{header, StatusCode}};
 use md5;
 use crate::url_utils::normalize_url;
-use crate::models::IconResponse;
-use crate::favicon::{get_page_icons, find_best_icon_for_size, select_user_agent_for_icon};
-use crate::validation::{validate_icons, validate_image_content, is_html_content};
-use crate::cache::IconCache;
+use crate::models::{Icon, IconResponse, BatchRequest, BatchResult, BatchResponse};
+use crate::favicon::{find_best_icon_for_size, fetch_theme_color};
+use crate::fetcher::{check_icon_url, discover_icons, download_icon, Download};
+use crate::validation::MAX_ICON_BYTES;
+use crate::cache::{IconCache, CacheEntry};
+use crate::config::{Config, DiscoveryConfig};
+use crate::error::GetIconError;
+use crate::failure::NegativeEntry;
+use crate::metrics::metrics;
+use crate::security::check_url_resolved;
+use crate::transform::{ImageOptions, OutputFormat, parse_flag, spawn_transform, MAX_RESIZE_DIMENSION};
+use crate::fallback::{Fallback, accepts_svg, blank_png, letter_avatar_svg, DEFAULT_AVATAR_SIZE, FALLBACK_HEADER};
 use std::env;
 use std::sync::Arc;
-// Remove unused Duration import
+use std::time::Duration;
 use bytes::Bytes;
 use std::collections::HashMap;
+use futures::{stream, StreamExt};
 use log::{warn, debug, error};
 
 /// Home page handler with documentation
@@ -35,10 +44,21 @@ pub async fn home() -> HttpResponse {
     <pre>/img?url=https://google.com</pre>
     <p>Optional: specify size with <code>size</code> parameter:</p>
     <pre>/img?url=https://google.com&size=192</pre>
+    <p>Optional: resample to exactly the requested size with <code>resize</code>:</p>
+    <pre>/img?url=https://google.com&size=192&resize=true</pre>
+    <p>Optional: convert to <code>png</code>, <code>webp</code>, <code>jpeg</code> or <code>ico</code> with <code>format</code> (defaults to the Accept header):</p>
+    <pre>/img?url=https://google.com&format=png</pre>
+    <p>Optional: serve a generated letter avatar instead of a 404 with <code>fallback=letter</code>:</p>
+    <pre>/img?url=https://google.com&fallback=letter</pre>
+    <p>Other fallbacks: <code>fallback=blank</code> (transparent PNG), <code>fallback=404</code> or <code>fallback=&lt;image url&gt;</code> (302 redirect).</p>
     
     <h3>Get favicon information as JSON:</h3>
     <pre>/json?url=https://google.com</pre>
     
+    <h3>Get favicon information for many sites at once:</h3>
+    <pre>POST /batch
+{"urls": ["google.com", "github.com"], "size": 32}</pre>
+    
     <h3>Health check endpoint:</h3>
     <pre>/health</pre>
 
@@ -79,7 +99,7 @@ pub async fn home() -> HttpResponse {
 }
 
 /// Extract important headers to forward to target sites
-fn extract_headers_to_forward(req: &HttpRequest) -> HashMap<String, String> {
+pub fn extract_headers_to_forward(req: &HttpRequest) -> HashMap<String, String> {
     let mut headers = HashMap::new();
     
     // Extract User-Agent
@@ -123,29 +143,136 @@ pub async fn get_favicon_img(
     url: web::Query<std::collections::HashMap<String, String>>,
     req: HttpRequest,
     client: web::Data<reqwest::Client>,
-    cache: web::Data<Arc<IconCache>>
+    cache: web::Data<Arc<IconCache>>,
+    config: web::Data<Config>
 ) -> HttpResponse {
     debug!("Image favicon request received");
     
     // Get and validate URL
     let url_str = match url.get("url") {
         Some(u) => u,
-        None => return HttpResponse::BadRequest().body("Missing url parameter"),
+        None => return GetIconError::InvalidRequest("Missing url parameter".to_string()).error_response(None),
     };
     
     let normalized_url = match normalize_url(url_str).await {
         Some(u) => u,
-        None => return HttpResponse::BadRequest().body("Invalid URL"),
+        None => return GetIconError::InvalidUrl.error_response(None),
     };
     
     // Get size parameter if provided
     let requested_size = url.get("size").and_then(|s| s.parse::<u32>().ok());
     
-    // Create a cache key that includes the size parameter if provided
-    let cache_key = match requested_size {
-        Some(size) => format!("{}:{}", normalized_url, size),
-        None => normalized_url.to_string(),
+    // Get output format if provided, otherwise negotiate it from the Accept header
+    let requested_format = match url.get("format") {
+        Some(f) => match OutputFormat::from_param(f) {
+            Some(format) => Some(format),
+            None => return GetIconError::InvalidRequest("Invalid format, expected one of: png, webp, jpeg, ico".to_string()).error_response(Some(&normalized_url)),
+        },
+        None => None,
+    };
+    let accept = req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok());
+    
+    // Get fallback mode if provided, otherwise use the server-wide default
+    let fallback = match url.get("fallback") {
+        Some(f) => match Fallback::from_param(f) {
+            Some(fallback) => Some(fallback),
+            None => return GetIconError::InvalidRequest(
+                "Invalid fallback, expected one of: 404, letter, blank or an http(s) image URL".to_string()
+            ).error_response(Some(&normalized_url)),
+        },
+        None => config.fallback.default_fallback(),
+    };
+    
+    // Resample to exactly the requested size when asked to
+    let options = ImageOptions::new(requested_size, parse_flag(url.get("resize")))
+        .with_format(requested_format)
+        .with_accept(accept);
+    if options.resize_target().is_some_and(|size| size == 0 || size > MAX_RESIZE_DIMENSION) {
+        return GetIconError::InvalidRequest(format!("Size must be between 1 and {} when resizing", MAX_RESIZE_DIMENSION))
+            .error_response(Some(&normalized_url));
+    }
+    
+    let response = fetch_favicon_img(&normalized_url, &options, &req, &client, &cache, &config).await;
+    
+    // Replace "not found" and upstream failures with the requested fallback image
+    // Fallbacks are never stored under the real icon's cache key
+    let status = response.status();
+    if status != StatusCode::NOT_FOUND && !status.is_server_error() {
+        return response;
+    }
+    match &fallback {
+        Some(Fallback::Letter) => {
+            debug!("Serving letter avatar fallback for: {} (status {})", normalized_url, status);
+            // Server errors mean the site itself failed (DNS, connection, TLS, timeout), so don't
+            // wait on its page again just to color the avatar
+            let fetch_theme = !status.is_server_error();
+            letter_fallback_response(&normalized_url, &options, &req, &client, &cache, &config, fetch_theme).await
+        },
+        Some(Fallback::Blank) => {
+            debug!("Serving blank fallback for: {} (status {})", normalized_url, status);
+            match blank_png(options.size) {
+                Some(bytes) => HttpResponse::Ok()
+                    .content_type("image/png")
+                    .append_header((header::CACHE_CONTROL, cache_control(&config, Freshness::Fallback)))
+                    .append_header((FALLBACK_HEADER, Fallback::Blank.as_str()))
+                    .body(bytes),
+                None => response,
+            }
+        },
+        Some(redirect @ Fallback::Redirect(target)) => {
+            debug!("Redirecting to fallback image {} for: {} (status {})", target, normalized_url, status);
+            HttpResponse::Found()
+                .append_header((header::LOCATION, target.as_str()))
+                .append_header((header::CACHE_CONTROL, cache_control(&config, Freshness::Fallback)))
+                .append_header((FALLBACK_HEADER, redirect.as_str()))
+                .finish()
+        },
+        Some(Fallback::NotFound) | None => response,
+    }
+}
+
+/// Which of the configured client max-ages a response gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Freshness {
+    Fresh,
+    Stale,
+    Fallback,
+}
+
+/// Cache-Control value for a response, with the max-age from `[client_cache]`
+fn cache_control(config: &Config, freshness: Freshness) -> String {
+    let max_age = match freshness {
+        Freshness::Fresh => config.client_cache.max_age_seconds,
+        Freshness::Stale => config.client_cache.stale_max_age_seconds,
+        Freshness::Fallback => config.client_cache.fallback_max_age_seconds,
     };
+    format!("public, max-age={}", max_age)
+}
+
+/// 304 response with the ETag, Cache-Control and Vary headers the full response would have had
+fn not_modified(etag: &str, cache_control: &str, options: &ImageOptions) -> HttpResponseBuilder {
+    let mut response = HttpResponse::NotModified();
+    response
+        .append_header((header::CACHE_CONTROL, cache_control))
+        .append_header((header::ETAG, etag));
+    if options.varies_by_accept() {
+        response.append_header((header::VARY, "Accept"));
+    }
+    response
+}
+
+/// Serves the best icon for a URL from the cache, fetching and caching it on a miss
+/// Returns a 404 or 5xx response when no usable icon could be served
+async fn fetch_favicon_img(
+    normalized_url: &url::Url,
+    options: &ImageOptions,
+    req: &HttpRequest,
+    client: &web::Data<reqwest::Client>,
+    cache: &web::Data<Arc<IconCache>>,
+    config: &web::Data<Config>
+) -> HttpResponse {
+    // Create a cache key that includes the size and resize parameters if provided
+    let cache_key = options.cache_key(normalized_url.as_str());
     
     // Check if the icon is in the cache (either main or expired)
     match cache.get(&cache_key).await {
@@ -154,9 +281,7 @@ pub async fn get_favicon_img(
             if let Some(if_none_match) = req.headers().get(header::IF_NONE_MATCH) {
                 if if_none_match.to_str().unwrap_or("") == cached_entry.etag {
                     debug!("Client already has latest version (ETag match): {}", cache_key);
-                    return HttpResponse::NotModified()
-                        .append_header((header::CACHE_CONTROL, "public, max-age=7200"))
-                        .finish();
+                    return not_modified(&cached_entry.etag, &cache_control(config, Freshness::Fresh), options).finish();
                 }
             }
             
@@ -165,133 +290,66 @@ pub async fn get_favicon_img(
                 debug!("Serving from expired cache while refreshing: {}", cache_key);
                 
                 // Extract headers to forward for the background task
-                let forwarded_headers = extract_headers_to_forward(&req);
+                let forwarded_headers = extract_headers_to_forward(req);
                 
                 // Clone variables for background task
                 let cache_clone = cache.clone();
                 let cache_key_clone = cache_key.clone();
                 let client_clone = client.clone();
                 let normalized_url_clone = normalized_url.clone();
-                let forwarded_headers_clone = forwarded_headers.clone();
-                let requested_size_clone = requested_size;
+                let options_clone = options.clone();
+                let config_clone = config.clone();
+                let stale_entry = cached_entry.clone();
                 
                 // Launch background task to refresh the entry
+                // Refreshes and misses for the same key share a single fetch
                 actix_web::rt::spawn(async move {
                     debug!("Background refresh task started for: {}", cache_key_clone);
                     
-                    // Fetch icons from website
-                    let icons = match get_page_icons(
-                        client_clone.as_ref(), 
-                        &normalized_url_clone, 
-                        Some(&forwarded_headers_clone), 
-                        None
-                    ).await {
-                        icons if !icons.is_empty() => icons,
-                        _ => {
-                            debug!("Background refresh: no icons found");
-                            return;
-                        }
-                    };
-                    
-                    // Validate icons
-                    let validated_icons = validate_icons(
-                        client_clone.as_ref(), 
-                        &icons, 
-                        &forwarded_headers_clone
-                    ).await;
-                    
-                    if validated_icons.is_empty() {
-                        debug!("Background refresh: no valid icons found");
-                        return;
-                    }
-                    
-                    // Select best icon
-                    let best_icon = match find_best_icon_for_size(&validated_icons, requested_size_clone) {
-                        Some(icon) => icon,
-                        None => {
-                            debug!("Background refresh: no suitable icon found");
-                            return;
-                        }
-                    };
-                    
-                    // Create a copy of forwarded headers that we can modify
-                    let mut headers = forwarded_headers_clone.clone();
-                    
-                    // Override the User-Agent with our selected one based on icon type
-                    headers.insert("User-Agent".to_string(), select_user_agent_for_icon(best_icon).to_string());
-                    
-                    // Fetch the icon
-                    let mut request_builder = client_clone.get(&best_icon.url);
-                    
-                    // Apply headers
-                    for (name, value) in &headers {
-                        request_builder = request_builder.header(name, value);
-                    }
-                    
-                    // Send the request
-                    match request_builder.send().await {
-                        Ok(response) => {
-                            // Verify response is valid
-                            if !response.status().is_success() {
-                                debug!("Background refresh: icon request failed with status {}", response.status());
-                                return;
-                            }
-                            
-                            match response.bytes().await {
-                                Ok(bytes) => {
-                                    // Validate content
-                                    if bytes.is_empty() || is_html_content(&bytes) {
-                                        debug!("Background refresh: invalid icon content");
-                                        return;
-                                    }
-                                    
-                                    let is_valid_image = validate_image_content(&bytes, &best_icon.content_type);
-                                    if !is_valid_image {
-                                        debug!("Background refresh: invalid image content");
-                                        return;
-                                    }
-                                    
-                                    let etag = format!("\"{:x}\"", md5::compute(&bytes));
-                                    
-                                    // Update main cache with the new content
-                                    cache_clone.insert(
-                                        cache_key_clone.clone(), // Clone since we need to use key again
-                                        bytes,
-                                        best_icon.content_type.clone(),
-                                        etag
-                                    ).await;
-                                    
-                                    // Remove from expired cache since it's now in main cache
-                                    cache_clone.remove_from_expired(&cache_key_clone).await;
-                                    
-                                    debug!("Background refresh completed successfully");
-                                },
-                                Err(err) => {
-                                    debug!("Background refresh: Failed to read icon content: {}", err);
-                                }
-                            }
+                    let fetch = fetch_icon_entry(
+                        client_clone.as_ref(),
+                        cache_clone.as_ref(),
+                        &config_clone.discovery,
+                        &normalized_url_clone,
+                        &options_clone,
+                        &forwarded_headers,
+                        Some(&stale_entry)
+                    );
+                    match cache_clone.get_or_fetch(&cache_key_clone, fetch).await {
+                        Ok(_) => {
+                            // Remove from expired cache since it's now in main cache
+                            cache_clone.remove_from_expired(&cache_key_clone).await;
+                            debug!("Background refresh completed successfully");
                         },
                         Err(err) => {
-                            debug!("Background refresh: Failed to fetch icon: {}", err);
+                            debug!("Background refresh failed: {}", err);
                         }
                     }
                 });
                 
-                // Return the expired cached icon with a shorter cache duration (10 minutes)
-                return HttpResponse::Ok()
+                // Return the expired cached icon with a shorter cache duration
+                let mut response = HttpResponse::Ok();
+                response
                     .content_type(cached_entry.content_type.as_str())
-                    .append_header((header::CACHE_CONTROL, "public, max-age=600")) // 10 minutes
-                    .append_header((header::ETAG, cached_entry.etag.clone()))
-                    .body(cached_entry.content.clone());
+                    .append_header((header::CACHE_CONTROL, cache_control(config, Freshness::Stale)))
+                    .append_header((header::ETAG, cached_entry.etag.clone()));
+                if options.varies_by_accept() {
+                    response.append_header((header::VARY, "Accept"));
+                }
+                return response.body(cached_entry.content.clone());
             }
             
             // If from main cache, return normal TTL
             debug!("Serving from main cache: {}", cache_key);
-            return HttpResponse::Ok()
+            let mut response = HttpResponse::Ok();
+            response
                 .content_type(cached_entry.content_type.as_str())
-                .append_header((header::CACHE_CONTROL, "public, max-age=7200"))
-                .append_header((header::ETAG, cached_entry.etag.clone()))
-                .body(cached_entry.content.clone());
+                .append_header((header::CACHE_CONTROL, cache_control(config, Freshness::Fresh)))
+                .append_header((header::ETAG, cached_entry.etag.clone()));
+            if options.varies_by_accept() {
+                response.append_header((header::VARY, "Accept"));
+            }
+            return response.body(cached_entry.content.clone());
         },
         None => {
             // No cache hit in either main or expired cache
@@ -299,268 +357,261 @@ pub async fn get_favicon_img(
     }
     
     // Check if this URL is in the negative cache (previously failed)
-    if cache.is_negative(&cache_key).await {
-        debug!("URL in negative cache, returning 404: {}", cache_key);
-        return HttpResponse::NotFound().body("Icon not found (cached negative result)");
+    if let Some(negative) = cache.negative(&cache_key).await {
+        return negative_error(&cache_key, &negative).error_response(Some(normalized_url));
+    }
+    
+    // Refuse to fetch from private, loopback and link-local addresses
+    if let Err(err) = check_url_resolved(normalized_url).await {
+        warn!("Blocked icon request for URL: {}", normalized_url);
+        return GetIconError::from(err).error_response(Some(normalized_url));
     }
     
     // Extract headers to forward
-    let forwarded_headers = extract_headers_to_forward(&req);
+    let forwarded_headers = extract_headers_to_forward(req);
+    
+    // Concurrent misses on the same key share a single discovery and fetch
+    let fetch = fetch_icon_entry(
+        client.as_ref(),
+        cache.as_ref(),
+        &config.discovery,
+        normalized_url,
+        options,
+        &forwarded_headers,
+        None
+    );
+    let entry = match cache.get_or_fetch(&cache_key, fetch).await {
+        Ok(entry) => entry,
+        Err(err) => return err.error_response(Some(normalized_url)),
+    };
     
-    // If not in cache, fetch icons from the website
-    let icons = match get_page_icons(client.as_ref(), &normalized_url, Some(&forwarded_headers), None).await {
-        icons if !icons.is_empty() => icons,
-        _ => {
-            // Log the failure with more details
-            warn!("Failed to find icons for URL: {}", normalized_url);
-            
-            // Also send to Sentry if enabled
-            if env::var("SENTRY_DSN").is_ok() {
-                sentry::capture_message(
-                    &format!("Failed to find icons for URL: {}", normalized_url),
-                    sentry::Level::Warning
-                );
-            }
-            return HttpResponse::NotFound().body("No icons found")
+    // Also check if we should remove it from expired cache
+    cache.remove_from_expired(&cache_key).await;
+    
+    // Check if the client has the same version
+    if let Some(if_none_match) = req.headers().get(header::IF_NONE_MATCH) {
+        if if_none_match.to_str().unwrap_or("") == entry.etag {
+            return not_modified(&entry.etag, &cache_control(config, Freshness::Fresh), options).finish();
         }
+    }
+    
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(entry.content_type.as_str())
+        .append_header((header::CACHE_CONTROL, cache_control(config, Freshness::Fresh)))
+        .append_header((header::ETAG, entry.etag.clone()));
+    if options.varies_by_accept() {
+        response.append_header((header::VARY, "Accept"));
+    }
+    response.body(entry.content.clone())
+}
+
+/// Fetches and caches the default /img variant of a site ahead of the first request
+/// Fresh entries are left alone; stale ones are refreshed
+pub async fn prefetch_icon(
+    client: &reqwest::Client,
+    cache: &IconCache,
+    discovery: &DiscoveryConfig,
+    url_str: &str,
+    size: Option<u32>
+) -> Result<(), GetIconError> {
+    let normalized_url = match normalize_url(url_str).await {
+        Some(u) => u,
+        None => return Err(GetIconError::InvalidUrl),
+    };
+    let options = ImageOptions::new(size, false);
+    let cache_key = options.cache_key(normalized_url.as_str());
+    
+    let stale_entry = match cache.get(&cache_key).await {
+        Some((_, false)) => return Ok(()),
+        Some((entry, true)) => Some(entry),
+        None => None,
     };
     
-    // Validate icons
-    let validated_icons = validate_icons(client.as_ref(), &icons, &forwarded_headers).await;
+    check_url_resolved(&normalized_url).await?;
     
-    // If no icons passed validation, add to negative cache and return a 404
-    if validated_icons.is_empty() {
-        // Add to negative cache to avoid repeated validation attempts
-        let cache_key_for_log = cache_key.clone(); // Clone for logging
-        cache.insert_negative(cache_key).await;
-        debug!("No valid icons found, added to negative cache: {}", cache_key_for_log);
-        return HttpResponse::NotFound().body("No valid icons found");
+    let no_headers = HashMap::new();
+    let fetch = fetch_icon_entry(client, cache, discovery, &normalized_url, &options, &no_headers, stale_entry.as_deref());
+    cache.get_or_fetch(&cache_key, fetch).await.map_err(|err| err.as_ref().clone())?;
+    cache.remove_from_expired(&cache_key).await;
+    Ok(())
+}
+
+/// The error for a lookup that is backing off after a failure, with the failure's status and reason
+fn negative_error(cache_key: &str, negative: &NegativeEntry) -> GetIconError {
+    debug!("URL in negative cache ({}), returning {}: {}", negative.kind.as_str(), negative.kind.status(), cache_key);
+    GetIconError::Backoff(negative.kind, negative.remaining().as_secs().max(1))
+}
+
+/// Remembers why a lookup failed, so it is retried with growing delays
+/// Failures the site isn't to blame for are not remembered, and background refreshes
+/// (with a stale entry) keep serving the stale entry and record nothing
+async fn record_failure(cache: &IconCache, cache_key: &str, stale_entry: Option<&CacheEntry>, err: &GetIconError) {
+    let kind = match err.failure() {
+        Some(kind) if stale_entry.is_none() => kind,
+        _ => return,
+    };
+    let negative = cache.record_failure(cache_key.to_string(), kind).await;
+    debug!("Added to negative cache: {} ({}, attempt {}, retry in {}s)",
+        cache_key, kind.as_str(), negative.attempts, negative.retry_after.as_secs());
+}
+
+/// Reports a failed lookup to Sentry, if enabled
+fn report_failure(normalized_url: &url::Url, err: &GetIconError) {
+    if env::var("SENTRY_DSN").is_ok() {
+        let level = if err.status().is_server_error() { sentry::Level::Error } else { sentry::Level::Warning };
+        sentry::capture_message(&format!("Failed to get icon for URL: {}: {}", normalized_url, err), level);
+    }
+}
+
+/// Fetches and transforms the best icon for a URL from its discovered icons
+/// Returns the entry to cache, or why no usable icon could be fetched
+/// Background refreshes pass the stale entry: if the same icon is still the best match it is
+/// revalidated with a conditional request, and failures are not added to the negative cache
+/// Other failures are remembered with their kind, so repeated lookups back off
+async fn fetch_icon_entry(
+    client: &reqwest::Client,
+    cache: &IconCache,
+    discovery: &DiscoveryConfig,
+    normalized_url: &url::Url,
+    options: &ImageOptions,
+    forwarded_headers: &HashMap<String, String>,
+    stale_entry: Option<&CacheEntry>
+) -> Result<CacheEntry, GetIconError> {
+    let cache_key = &options.cache_key(normalized_url.as_str());
+    let result = download_best_icon(client, cache, discovery, normalized_url, options, forwarded_headers, stale_entry).await;
+    
+    // Add to negative cache to avoid repeated validation attempts
+    if let Err(err) = &result {
+        report_failure(normalized_url, err);
+        record_failure(cache, cache_key, stale_entry, err).await;
     }
+    result
+}
+
+/// Discovers, downloads and transforms the best icon for a URL, for `fetch_icon_entry`
+async fn download_best_icon(
+    client: &reqwest::Client,
+    cache: &IconCache,
+    discovery: &DiscoveryConfig,
+    normalized_url: &url::Url,
+    options: &ImageOptions,
+    forwarded_headers: &HashMap<String, String>,
+    stale_entry: Option<&CacheEntry>
+) -> Result<CacheEntry, GetIconError> {
+    let validated_icons = discover_icons(client, Some(cache), discovery, normalized_url, forwarded_headers).await?;
     
     // Select the best icon based on requested size or highest score from validated icons
-    let best_icon = match find_best_icon_for_size(&validated_icons, requested_size) {
-        Some(icon) => icon,
-        None => return HttpResponse::NotFound().body("No suitable icon found"),
+    let best_icon = find_best_icon_for_size(&validated_icons, options.size).ok_or(GetIconError::NoIcons)?;
+    check_icon_url(best_icon)?;
+    
+    // Ask the origin whether the icon we already have is still current
+    let revalidating = stale_entry.filter(|entry| {
+        entry.upstream.as_ref().is_some_and(|upstream| upstream.url == best_icon.url)
+    });
+    let upstream = revalidating.and_then(|entry| entry.upstream.as_ref());
+    let (bytes, upstream) = match download_icon(client, best_icon, forwarded_headers, upstream, MAX_ICON_BYTES).await? {
+        Download::Fetched { content, upstream } => (content, upstream),
+        // Unchanged at the origin: keep the cached content and ETag, and just extend its lifetime
+        Download::NotModified => match revalidating {
+            Some(entry) => {
+                debug!("Icon not modified at origin, extending cache entry: {}", options.cache_key(normalized_url.as_str()));
+                return Ok(entry.revalidated());
+            },
+            None => return Err(GetIconError::HttpStatus(StatusCode::NOT_MODIFIED.as_u16())),
+        },
     };
     
-    // Create a copy of forwarded headers that we can modify
-    let mut headers = forwarded_headers.clone();
-    
-    // Override the User-Agent with our selected one based on icon type
-    headers.insert("User-Agent".to_string(), select_user_agent_for_icon(best_icon).to_string());
+    record_icon_source(best_icon);
     
-    // Fetch the icon with the appropriate User-Agent
-    let mut request_builder = client.get(&best_icon.url);
+    // Extract ICO frames, resize and transcode as requested
+    let (bytes, content_type) = spawn_transform(best_icon, bytes, options).await;
+    let etag = format!("\"{:x}\"", md5::compute(&bytes));
     
-    // Apply headers
-    for (name, value) in &headers {
-        request_builder = request_builder.header(name, value);
+    Ok(CacheEntry {
+        upstream,
+        ..CacheEntry::new(bytes, content_type, etag)
+    })
+}
+
+/// Serves a generated letter avatar for a site without a usable icon
+/// Avatars are cached under their own key so they never shadow a real icon
+async fn letter_fallback_response(
+    normalized_url: &url::Url,
+    options: &ImageOptions,
+    req: &HttpRequest,
+    client: &web::Data<reqwest::Client>,
+    cache: &web::Data<Arc<IconCache>>,
+    config: &web::Data<Config>,
+    fetch_theme: bool
+) -> HttpResponse {
+    // Clients that explicitly accept SVG get the vector avatar unless a format was requested
+    let accept = req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok());
+    let serve_svg = options.format.is_none() && accepts_svg(accept);
+    let mut fallback_key = format!("fallback:letter:{}", options.cache_key(normalized_url.as_str()));
+    if serve_svg {
+        fallback_key.push_str(":svg");
     }
     
-    // Send the request
-    match request_builder.send().await {
-        Ok(response) => {
-            // Check if the response was redirected to a non-image resource
-            let final_url = response.url().to_string();
-            if final_url != best_icon.url {
-                // The request was redirected, check if the final URL is still an image
-                if let Some(content_type) = response.headers().get(header::CONTENT_TYPE) {
-                    if let Ok(content_type_str) = content_type.to_str() {
-                        // If redirected to a non-image resource (like HTML), reject it
-                        if !content_type_str.starts_with("image/") {
-                            // Log the redirect
-                            warn!("Icon redirected to non-image resource: {} -> {} (Content-Type: {})", 
-                                best_icon.url, final_url, content_type_str);
-                            
-                            // Also send to Sentry if enabled
-                            if env::var("SENTRY_DSN").is_ok() {
-                                sentry::capture_message(
-                                    &format!("Icon redirected to non-image resource: {} -> {} (Content-Type: {})", 
-                                        best_icon.url, final_url, content_type_str),
-                                    sentry::Level::Warning
-                                );
-                            }
-                            
-                            return HttpResponse::NotFound()
-                                .body(format!("Icon redirected to non-image resource: {}", final_url));
-                        }
-                    }
-                }
-            }
+    let (bytes, content_type, etag) = match cache.get(&fallback_key).await {
+        Some((cached_entry, _)) => (
+            cached_entry.content.clone(),
+            cached_entry.content_type.clone(),
+            cached_entry.etag.clone(),
+        ),
+        None => {
+            // Color the avatar with the page's theme-color when it declares one
+            let theme_color = if fetch_theme {
+                let forwarded_headers = extract_headers_to_forward(req);
+                let timeout = Duration::from_secs(config.fallback.theme_color_timeout_seconds);
+                fetch_theme_color(client.as_ref(), normalized_url, Some(&forwarded_headers), timeout).await
+            } else {
+                None
+            };
             
-            // Check content type header to ensure it's an image
-            if let Some(content_type) = response.headers().get(header::CONTENT_TYPE) {
-                if let Ok(content_type_str) = content_type.to_str() {
-                    if !content_type_str.starts_with("image/") {
-                        // Log the invalid content type
-                        warn!("Invalid content type for icon: {} (Content-Type: {})", 
-                            best_icon.url, content_type_str);
-                        
-                        // Also send to Sentry if enabled
-                        if env::var("SENTRY_DSN").is_ok() {
-                            sentry::capture_message(
-                                &format!("Invalid content type for icon: {} (Content-Type: {})", 
-                                    best_icon.url, content_type_str),
-                                sentry::Level::Warning
-                            );
-                        }
-                        
-                        return HttpResponse::NotFound()
-                            .body(format!("Invalid content type for icon: {}", content_type_str));
-                    }
-                }
-            }
+            let host = normalized_url.host_str().unwrap_or_default();
+            let size = options.size.filter(|s| *s > 0).unwrap_or(DEFAULT_AVATAR_SIZE).min(MAX_RESIZE_DIMENSION);
+            let svg = Bytes::from(letter_avatar_svg(host, theme_color.as_deref(), size));
             
-            if response.status().is_success() {
-                match response.bytes().await {
-                    Ok(bytes) => {
-                        // Validate content size
-                        if bytes.is_empty() {
-                            // Log the zero-size icon
-                            warn!("Zero-size icon detected for URL: {} from icon URL: {}", 
-                                normalized_url, best_icon.url);
-                            
-                            // Also send to Sentry if enabled
-                            if env::var("SENTRY_DSN").is_ok() {
-                                sentry::capture_message(
-                                    &format!("Zero-size icon detected for URL: {} from icon URL: {}", 
-                                        normalized_url, best_icon.url),
-                                    sentry::Level::Warning
-                                );
-                            }
-                            
-                            return HttpResponse::NotFound()
-                                .body("Icon found but has zero size");
-                        }
-                        
-                        // Check for HTML content disguised as an image
-                        if is_html_content(&bytes) {
-                            // Log the HTML content disguised as an image
-                            warn!("HTML content disguised as an image for URL: {} from icon URL: {}", 
-                                normalized_url, best_icon.url);
-                            
-                            // Also send to Sentry if enabled
-                            if env::var("SENTRY_DSN").is_ok() {
-                                sentry::capture_message(
-                                    &format!("HTML content disguised as an image for URL: {} from icon URL: {}", 
-                                        normalized_url, best_icon.url),
-                                    sentry::Level::Warning
-                                );
-                            }
-                            
-                            return HttpResponse::NotFound()
-                                .body("Icon found but content is HTML, not an image");
-                        }
-                        
-                        // Validate image content using our validation function
-                        let is_valid_image = validate_image_content(&bytes, &best_icon.content_type);
-                        
-                        if !is_valid_image {
-                            // Log the invalid image
-                            warn!("Invalid image content for URL: {} from icon URL: {}", 
-                                normalized_url, best_icon.url);
-                            
-                            // Also send to Sentry if enabled
-                            if env::var("SENTRY_DSN").is_ok() {
-                                sentry::capture_message(
-                                    &format!("Invalid image content for URL: {} from icon URL: {}", 
-                                        normalized_url, best_icon.url),
-                                    sentry::Level::Warning
-                                );
-                            }
-                            
-                            return HttpResponse::NotFound()
-                                .body("Icon found but content is not a valid image");
-                        }
-                        
-                        let etag = format!("\"{:x}\"", md5::compute(&bytes));
-                        
-                        // Check if the client has the same version
-                        if let Some(if_none_match) = req.headers().get(header::IF_NONE_MATCH) {
-                            if if_none_match.to_str().unwrap_or("") == etag {
-                                return HttpResponse::NotModified().finish();
-                            }
-                        }
-                        
-                        // Store in main cache, and if it was in expired cache, remove it from there
-                        cache.insert(
-                            cache_key.clone(), // Clone since we need the key again
-                            bytes.clone(),
-                            best_icon.content_type.clone(),
-                            etag.clone()
-                        ).await;
-                        
-                        // Also check if we should remove it from expired cache
-                        cache.remove_from_expired(&cache_key).await;
-
-                        HttpResponse::Ok()
-                            .content_type(best_icon.content_type.as_str())
-                            .append_header((header::CACHE_CONTROL, "public, max-age=7200"))
-                            .append_header((header::ETAG, etag))
-                            .body(bytes)
-                    },
-                    Err(err) => {
-                        // Log the error
-                        error!("Failed to read icon content: {}", err);
-                        
-                        // Also send to Sentry if enabled
-                        if env::var("SENTRY_DSN").is_ok() {
-                            sentry::capture_message(
-                                &format!("Failed to read icon content: {}", err),
-                                sentry::Level::Error
-                            );
-                        }
-                        
-                        HttpResponse::InternalServerError()
-                            .body("Failed to read icon content")
-                    }
-                }
+            let (bytes, content_type) = if serve_svg {
+                (svg, "image/svg+xml".to_string())
             } else {
-                let status = response.status();
-                
-                // Log the error
-                warn!("Icon not found. Status: {}", status);
-                
-                // Also send to Sentry if enabled
-                if env::var("SENTRY_DSN").is_ok() {
-                    sentry::capture_message(
-                        &format!("Icon not found. Status: {}", status),
-                        sentry::Level::Warning
-                    );
+                // Rasterize to PNG unless another format was requested or negotiated
+                let mut avatar_options = options.clone();
+                avatar_options.size = Some(size);
+                if avatar_options.format.is_none() && avatar_options.accepted.is_empty() {
+                    avatar_options.format = Some(OutputFormat::Png);
                 }
-                
-                HttpResponse::NotFound()
-                    .body(format!("Icon not found. Status: {}", status))
-            }
-        }
-        Err(err) => {
-            // Log the error
-            error!("Failed to fetch icon: {}", err);
-            
-            // Also send to Sentry if enabled
-            if env::var("SENTRY_DSN").is_ok() {
-                sentry::capture_message(
-                    &format!("Failed to fetch icon: {}", err),
-                    sentry::Level::Error
-                );
-            }
+                let avatar = Icon::new(normalized_url.to_string(), "image/svg+xml".to_string(), Some(size), Some(size));
+                spawn_transform(&avatar, svg, &avatar_options).await
+            };
+            let etag = format!("\"{:x}\"", md5::compute(&bytes));
             
-            // Determine appropriate status code based on error type
-            if err.is_timeout() {
-                warn!("Request timed out while fetching icon: {}", err);
-                HttpResponse::GatewayTimeout()
-                    .body(format!("Request timed out while fetching icon: {}", err))
-            } else if err.is_connect() {
-                warn!("Connection error while fetching icon: {}", err);
-                HttpResponse::BadGateway()
-                    .body(format!("Connection error while fetching icon: {}", err))
-            } else {
-                error!("Failed to fetch icon: {}", err);
-                HttpResponse::InternalServerError()
-                    .body(format!("Failed to fetch icon: {}", err))
-            }
+            cache.insert(fallback_key, bytes.clone(), content_type.clone(), etag.clone()).await;
+            (bytes, content_type, etag)
+        }
+    };
+    
+    // Check if the client has the same version
+    if let Some(if_none_match) = req.headers().get(header::IF_NONE_MATCH) {
+        if if_none_match.to_str().unwrap_or("") == etag {
+            return not_modified(&etag, &cache_control(config, Freshness::Fallback), options)
+                .append_header((FALLBACK_HEADER, Fallback::Letter.as_str()))
+                .finish();
         }
     }
+    
+    // Shorter TTL so a real icon replaces the avatar once the site gets one
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(content_type)
+        .append_header((header::CACHE_CONTROL, cache_control(config, Freshness::Fallback)))
+        .append_header((header::ETAG, etag))
+        .append_header((FALLBACK_HEADER, Fallback::Letter.as_str()));
+    if options.varies_by_accept() {
+        response.append_header((header::VARY, "Accept"));
+    }
+    response.body(bytes)
 }
 
 /// Health check endpoint
@@ -569,7 +620,23 @@ pub async fn get_favicon_img(
         debug!("Health check requested");
         
         // Get cache statistics for monitoring
-        let (main_count, expired_count, negative_count) = cache.stats().await;
+        // Backends that can't count their entries cheaply (Redis) report null
+        let (main_count, expired_count, negative_count) = match cache.stats().await {
+            Some((main, expired, negative)) => (main.to_string(), expired.to_string(), negative.to_string()),
+            None => ("null".to_string(), "null".to_string(), "null".to_string()),
+        };
+        let discovery_count = cache.discovery_count().await;
+        let disk_stats = match cache.disk_stats() {
+            Some((entries, bytes)) => format!(r#"{{"entries":{},"bytes":{}}}"#, entries, bytes),
+            None => "null".to_string(),
+        };
+        let memory_stats = match cache.byte_usage().await {
+            Some(usage) => format!(
+                r#"{{"max_bytes_per_layer":{},"main_bytes":{},"expired_bytes":{}}}"#,
+                usage.max_bytes, usage.main_bytes, usage.expired_bytes
+            ),
+            None => "null".to_string(),
+        };
         
         HttpResponse::Ok()
             .content_type("application/json")
@@ -580,10 +647,13 @@ pub async fn get_favicon_img(
                     "cache_stats":{{
                         "main_cache":{},
                         "expired_cache":{},
-                        "negative_cache":{}
+                        "negative_cache":{},
+                        "discovery_cache":{},
+                        "disk_cache":{},
+                        "memory":{}
                     }}
                 }}"#,
-                main_count, expired_count, negative_count
+                main_count, expired_count, negative_count, discovery_count, disk_stats, memory_stats
             ))
     }
 
@@ -593,80 +663,142 @@ pub async fn get_favicon_json(
     url: web::Query<std::collections::HashMap<String, String>>,
     req: HttpRequest,
     client: web::Data<reqwest::Client>,
-    cache: web::Data<Arc<IconCache>>
+    cache: web::Data<Arc<IconCache>>,
+    config: web::Data<Config>
 ) -> HttpResponse {
     debug!("JSON favicon request received");
     
     // Get and validate URL
     let url_str = match url.get("url") {
         Some(u) => u,
-        None => return HttpResponse::BadRequest().body("Missing url parameter"),
+        None => return GetIconError::InvalidRequest("Missing url parameter".to_string()).error_response(None),
     };
     
     let normalized_url = match normalize_url(url_str).await {
         Some(u) => u,
-        None => return HttpResponse::BadRequest().body("Invalid URL"),
+        None => return GetIconError::InvalidUrl.error_response(None),
     };
     
     // Get size parameter if provided
     let requested_size = url.get("size").and_then(|s| s.parse::<u32>().ok());
     
-    // Create a cache key that includes the size parameter if provided
-    let cache_key = match requested_size {
-        Some(size) => format!("{}:json:{}", normalized_url, size),
-        None => format!("{}:json", normalized_url),
-    };
+    // Extract headers to forward
+    let forwarded_headers = extract_headers_to_forward(&req);
+    
+    match lookup_icon_json(client.as_ref(), cache.as_ref(), &config.discovery, &normalized_url, requested_size, &forwarded_headers).await {
+        Ok((entry, freshness)) => HttpResponse::Ok()
+            .content_type(entry.content_type.as_str())
+            .append_header((header::CACHE_CONTROL, cache_control(&config, freshness)))
+            .append_header((header::ETAG, entry.etag.clone()))
+            .body(entry.content.clone()),
+        Err(err) => err.error_response(Some(&normalized_url)),
+    }
+}
+
+/// Cached /json document with the freshness to serve it with, or why the lookup failed
+type JsonLookupResult = Result<(Arc<CacheEntry>, Freshness), GetIconError>;
+
+/// Looks up icon information for a URL, serving the cached /json document when present
+async fn lookup_icon_json(
+    client: &reqwest::Client,
+    cache: &Arc<IconCache>,
+    discovery: &DiscoveryConfig,
+    normalized_url: &url::Url,
+    requested_size: Option<u32>,
+    forwarded_headers: &HashMap<String, String>
+) -> JsonLookupResult {
+    let cache_key = json_cache_key(normalized_url, requested_size);
     
     // Check if the response is in the cache
     if let Some((cached_entry, needs_refresh)) = cache.get(&cache_key).await {
         // For JSON endpoint, we'll use the same approach as for images
-        // If from expired cache, return a shorter TTL
-        let max_age = if needs_refresh { "600" } else { "3600" };
-        
-        // Return the cached JSON response
-        return HttpResponse::Ok()
-            .content_type(cached_entry.content_type.as_str())
-            .append_header((header::CACHE_CONTROL, format!("public, max-age={}", max_age)))
-            .append_header((header::ETAG, cached_entry.etag.clone()))
-            .body(cached_entry.content.clone());
+        // If from expired cache, refresh in the background and return a shorter TTL
+        if needs_refresh {
+            debug!("Serving from expired cache while refreshing: {}", cache_key);
+            let client = client.clone();
+            let cache = cache.clone();
+            let normalized_url = normalized_url.clone();
+            let forwarded_headers = forwarded_headers.clone();
+            let discovery = discovery.clone();
+            let stale_entry = cached_entry.clone();
+            actix_web::rt::spawn(async move {
+                let fetch = fetch_icon_json_entry(&client, &cache, &discovery, &normalized_url, requested_size, &forwarded_headers, Some(&stale_entry));
+                match cache.get_or_fetch(&cache_key, fetch).await {
+                    Ok(_) => {
+                        cache.remove_from_expired(&cache_key).await;
+                        debug!("Background refresh completed successfully");
+                    },
+                    Err(err) => debug!("Background refresh failed: {}", err),
+                }
+            });
+            return Ok((cached_entry, Freshness::Stale));
+        }
+        return Ok((cached_entry, Freshness::Fresh));
     }
     
-    // Extract headers to forward
-    let forwarded_headers = extract_headers_to_forward(&req);
+    // Check if this URL is in the negative cache (previously failed)
+    if let Some(negative) = cache.negative(&cache_key).await {
+        return Err(negative_error(&cache_key, &negative));
+    }
+    
+    // Refuse to fetch from private, loopback and link-local addresses
+    if let Err(err) = check_url_resolved(normalized_url).await {
+        warn!("Blocked icon request for URL: {}", normalized_url);
+        return Err(err.into());
+    }
     
-    // If not in cache, fetch icons from the website
-    let icons = match get_page_icons(client.as_ref(), &normalized_url, Some(&forwarded_headers), None).await {
-        icons if !icons.is_empty() => icons,
-        _ => {
-            warn!("Failed to find icons for URL: {}", normalized_url);
-            return HttpResponse::NotFound().body("No icons found");
+    // Concurrent misses on the same key share a single discovery
+    let fetch = fetch_icon_json_entry(client, cache, discovery, normalized_url, requested_size, forwarded_headers, None);
+    match cache.get_or_fetch(&cache_key, fetch).await {
+        Ok(entry) => Ok((entry, Freshness::Fresh)),
+        Err(err) => Err(err.as_ref().clone()),
+    }
+}
+
+/// Cache key of the /json document for a URL, including the size parameter if provided
+fn json_cache_key(normalized_url: &url::Url, requested_size: Option<u32>) -> String {
+    match requested_size {
+        Some(size) => format!("{}:json:{}", normalized_url, size),
+        None => format!("{}:json", normalized_url),
+    }
+}
+
+/// Counts where a served icon was found
+fn record_icon_source(icon: &Icon) {
+    metrics().record_icon_source(icon.source.map_or("unknown", |source| source.as_str()));
+}
+
+/// Builds the /json document for a URL from its discovered icons
+/// Failures are remembered like in `fetch_icon_entry`, except for background refreshes
+async fn fetch_icon_json_entry(
+    client: &reqwest::Client,
+    cache: &IconCache,
+    discovery: &DiscoveryConfig,
+    normalized_url: &url::Url,
+    requested_size: Option<u32>,
+    forwarded_headers: &HashMap<String, String>,
+    stale_entry: Option<&CacheEntry>
+) -> Result<CacheEntry, GetIconError> {
+    let cache_key = &json_cache_key(normalized_url, requested_size);
+    let final_icons = match discover_icons(client, Some(cache), discovery, normalized_url, forwarded_headers).await {
+        Ok(icons) => icons,
+        Err(err) => {
+            report_failure(normalized_url, &err);
+            record_failure(cache, cache_key, stale_entry, &err).await;
+            return Err(err);
         }
     };
     
-    // Select best icon based on requested size or highest score
-    let _best_icon = find_best_icon_for_size(&icons, requested_size)
-        .cloned();
-    
-    // Validate icons
-    let final_icons = validate_icons(client.as_ref(), &icons, &forwarded_headers).await;
-    
-    // If no icons passed validation, return a 404
-    if final_icons.is_empty() {
-        warn!("No valid icons found for URL: {}", normalized_url);
-        return HttpResponse::NotFound().body("No valid icons found");
+    // Calculate the best icon based on the validated icons
+    let best_icon = find_best_icon_for_size(&final_icons, requested_size).cloned();
+    if let Some(icon) = &best_icon {
+        record_icon_source(icon);
     }
     
-    // Recalculate the best icon based on the validated icons
-    let best_icon = if !final_icons.is_empty() {
-        find_best_icon_for_size(&final_icons, requested_size).cloned()
-    } else {
-        None
-    };
-    
     // Create response
     let response = IconResponse {
-        url: normalized_url.host_str().unwrap_or(url_str).to_string(),
-        icons: final_icons,
+        url: normalized_url.host_str().unwrap_or_default().to_string(),
+        icons: final_icons.to_vec(),
         best_icon,
     };
     
@@ -675,19 +807,8 @@ pub async fn get_favicon_json(
             // Generate ETag for the JSON response
             let etag = format!("\"{:x}\"", md5::compute(json.as_bytes()));
             
-            // Store in cache (as bytes for consistency with the image endpoint)
-            cache.insert(
-                cache_key,
-                Bytes::from(json.clone()),
-                "application/json".to_string(),
-                etag.clone()
-            ).await;
-            
-            HttpResponse::Ok()
-                .content_type("application/json")
-                .append_header((header::CACHE_CONTROL, "public, max-age=7200"))
-                .append_header((header::ETAG, etag))
-                .body(json)
+            // Stored in cache as bytes for consistency with the image endpoint
+            Ok(CacheEntry::new(Bytes::from(json), "application/json".to_string(), etag))
         },
         Err(err) => {
             // Log the error
@@ -701,8 +822,85 @@ pub async fn get_favicon_json(
                 );
             }
             
-            HttpResponse::InternalServerError()
-                .body(format!("Failed to generate JSON response: {}", err))
+            Err(GetIconError::Internal(format!("Failed to generate JSON response: {}", err)))
+        }
+    }
+}
+
+/// Handler for /batch endpoint - returns favicon information for many URLs at once
+#[post("/batch")]
+pub async fn batch_lookup(
+    body: web::Json<BatchRequest>,
+    req: HttpRequest,
+    client: web::Data<reqwest::Client>,
+    cache: web::Data<Arc<IconCache>>,
+    config: web::Data<Config>
+) -> HttpResponse {
+    let batch = body.into_inner();
+    debug!("Batch request received for {} URLs", batch.urls.len());
+    
+    if batch.urls.len() > config.batch.max_urls {
+        return GetIconError::InvalidRequest(format!("Too many URLs, at most {} are allowed per batch", config.batch.max_urls)).error_response(None);
+    }
+    
+    let forwarded_headers = extract_headers_to_forward(&req);
+    let total = batch.urls.len();
+    
+    // Look up URLs with bounded concurrency, keeping track of each one's position
+    let mut lookups = stream::iter(batch.urls.iter().enumerate())
+        .map(|(index, url_str)| {
+            let client = client.clone();
+            let cache = cache.clone();
+            let forwarded_headers = &forwarded_headers;
+            let discovery = &config.discovery;
+            async move {
+                let result = match normalize_url(url_str).await {
+                    Some(normalized_url) => lookup_icon_json(client.as_ref(), cache.as_ref(), discovery, &normalized_url, batch.size, forwarded_headers).await,
+                    None => Err(GetIconError::InvalidUrl),
+                };
+                (index, result)
+            }
+        })
+        .buffer_unordered(config.batch.concurrency);
+    
+    // Stop waiting once the batch time limit is reached; unfinished URLs are reported as timed out
+    let mut outcomes: Vec<Option<JsonLookupResult>> = vec![None; total];
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(config.batch.timeout_seconds);
+    loop {
+        match tokio::time::timeout_at(deadline, lookups.next()).await {
+            Ok(Some((index, result))) => outcomes[index] = Some(result),
+            Ok(None) => break,
+            Err(_) => {
+                warn!("Batch time limit reached with {} of {} URLs done", outcomes.iter().filter(|o| o.is_some()).count(), total);
+                break;
+            }
         }
     }
+    
+    let results = batch.urls.iter().zip(outcomes).map(|(url_str, outcome)| {
+        let mut result = BatchResult { url: url_str.clone(), result: None, code: None, error: None, status: None };
+        match outcome {
+            Some(Ok((entry, _))) => match serde_json::from_slice(&entry.content) {
+                Ok(value) => result.result = Some(value),
+                Err(err) => {
+                    result.code = Some("internal".to_string());
+                    result.error = Some(format!("Failed to read cached response: {}", err));
+                    result.status = Some(StatusCode::INTERNAL_SERVER_ERROR.as_u16());
+                }
+            },
+            Some(Err(err)) => {
+                result.code = Some(err.code().to_string());
+                result.error = Some(err.to_string());
+                result.status = Some(err.status().as_u16());
+            },
+            None => {
+                result.code = Some("timeout".to_string());
+                result.error = Some("Batch time limit exceeded".to_string());
+                result.status = Some(StatusCode::GATEWAY_TIMEOUT.as_u16());
+            }
+        }
+        result
+    }).collect();
+    
+    HttpResponse::Ok().json(BatchResponse { results })
 }
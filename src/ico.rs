@@ -0,0 +1,127 @@
+use bytes::Bytes;
+use std::io::Cursor;
+use image::ImageOutputFormat;
+use crate::models::Icon;
+use crate::transform::decode_image;
+use log::debug;
+
+/// Size of the ICONDIR header at the start of every ICO/CUR file
+const ICO_HEADER_SIZE: usize = 6;
+
+/// Size of each ICONDIRENTRY in the icon directory
+const ICO_ENTRY_SIZE: usize = 16;
+
+/// A single image embedded in an ICO file, as listed in the icon directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IcoFrame {
+    pub index: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bit_count: u16,
+    pub offset: usize,
+    pub length: usize,
+}
+
+/// Parses the icon directory of an ICO or CUR file
+/// Only the directory itself needs to be present, frame data may be truncated
+pub fn parse_ico_directory(bytes: &[u8]) -> Option<Vec<IcoFrame>> {
+    let header = bytes.get(..ICO_HEADER_SIZE)?;
+    let reserved = u16::from_le_bytes([header[0], header[1]]);
+    let kind = u16::from_le_bytes([header[2], header[3]]);
+    let count = u16::from_le_bytes([header[4], header[5]]) as usize;
+
+    // Type 1 is an icon, type 2 is a cursor
+    if reserved != 0 || (kind != 1 && kind != 2) || count == 0 {
+        return None;
+    }
+
+    let mut frames = Vec::with_capacity(count);
+    for index in 0..count {
+        let start = ICO_HEADER_SIZE + index * ICO_ENTRY_SIZE;
+        let entry = bytes.get(start..start + ICO_ENTRY_SIZE)?;
+
+        frames.push(IcoFrame {
+            index: index as u32,
+            // A stored size of 0 means 256 pixels
+            width: if entry[0] == 0 { 256 } else { entry[0] as u32 },
+            height: if entry[1] == 0 { 256 } else { entry[1] as u32 },
+            bit_count: u16::from_le_bytes([entry[6], entry[7]]),
+            length: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize,
+            offset: u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as usize,
+        });
+    }
+
+    debug!("Parsed ICO directory with {} frames", frames.len());
+    Some(frames)
+}
+
+/// Expands an ICO icon into one candidate per distinct frame size
+/// When several frames share a size, the one with the highest color depth is kept
+pub fn expand_ico_frames(icon: &Icon, frames: &[IcoFrame]) -> Vec<Icon> {
+    let mut best_frames: Vec<&IcoFrame> = Vec::new();
+    for frame in frames {
+        match best_frames.iter_mut().find(|f| f.width == frame.width && f.height == frame.height) {
+            Some(existing) if existing.bit_count < frame.bit_count => *existing = frame,
+            Some(_) => {},
+            None => best_frames.push(frame),
+        }
+    }
+
+    best_frames
+        .into_iter()
+        .map(|frame| {
+            let mut frame_icon = icon.clone();
+            frame_icon.width = Some(frame.width);
+            frame_icon.height = Some(frame.height);
+            frame_icon.frame = Some(frame.index);
+            frame_icon.calculate_score();
+            frame_icon
+        })
+        .collect()
+}
+
+/// Extracts a single frame from an ICO file and returns it encoded as PNG
+/// PNG-compressed frames are returned as-is, BMP frames are decoded and re-encoded
+pub fn extract_ico_frame_png(bytes: &[u8], index: u32) -> Option<Bytes> {
+    let frames = parse_ico_directory(bytes)?;
+    let frame = frames.get(index as usize)?;
+    let data = bytes.get(frame.offset..frame.offset.checked_add(frame.length)?)?;
+
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        debug!("ICO frame {} is PNG-compressed ({}x{})", index, frame.width, frame.height);
+        return Some(Bytes::copy_from_slice(data));
+    }
+
+    // Wrap the BMP frame in a single-frame ICO so the image crate decodes exactly this frame
+    let entry_start = ICO_HEADER_SIZE + index as usize * ICO_ENTRY_SIZE;
+    let mut single = Vec::with_capacity(ICO_HEADER_SIZE + ICO_ENTRY_SIZE + data.len());
+    single.extend_from_slice(&[0, 0, 1, 0, 1, 0]);
+    single.extend_from_slice(&bytes[entry_start..entry_start + 12]);
+    single.extend_from_slice(&((ICO_HEADER_SIZE + ICO_ENTRY_SIZE) as u32).to_le_bytes());
+    single.extend_from_slice(data);
+
+    let image = match decode_image(&single) {
+        Ok(image) => image,
+        Err(err) => {
+            debug!("Failed to decode ICO frame {}: {:?}", index, err);
+            return None;
+        }
+    };
+
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageOutputFormat::Png).ok()?;
+    debug!("Converted ICO frame {} ({}x{}) to PNG", index, frame.width, frame.height);
+    Some(Bytes::from(png.into_inner()))
+}
+
+/// Returns the bytes and content type to serve for an icon
+/// Icons that refer to a single ICO frame are served as that frame in PNG format
+pub fn icon_payload(icon: &Icon, bytes: Bytes) -> (Bytes, String) {
+    if let Some(index) = icon.frame {
+        match extract_ico_frame_png(&bytes, index) {
+            Some(png) => return (png, "image/png".to_string()),
+            None => debug!("Could not extract ICO frame {} from {}, serving whole file", index, icon.url),
+        }
+    }
+    (bytes, icon.content_type.clone())
+}
@@ -1,13 +1,47 @@
 pub mod url_utils;
 pub mod models;
+pub mod config;
 pub mod favicon;
+pub mod fetcher;
+#[cfg(feature = "server")]
 pub mod handlers;
+#[cfg(feature = "server")]
+pub mod admin;
+pub mod metrics;
+pub mod explain;
 pub mod cache;
+pub mod disk_cache;
+pub mod redis_cache;
+pub mod failure;
+pub mod error;
 pub mod validation;
+pub mod security;
+pub mod dimensions;
+pub mod ico;
+pub mod transform;
+pub mod svg;
+pub mod fallback;
 
 pub use url_utils::*;
 pub use models::*;
+pub use config::*;
 pub use favicon::*;
+pub use fetcher::*;
+#[cfg(feature = "server")]
 pub use handlers::*;
+#[cfg(feature = "server")]
+pub use admin::*;
+pub use metrics::*;
+pub use explain::*;
 pub use cache::*;
+pub use disk_cache::*;
+pub use redis_cache::*;
+pub use failure::*;
+pub use error::*;
 pub use validation::*;
+pub use security::*;
+pub use dimensions::*;
+pub use ico::*;
+pub use transform::*;
+pub use svg::*;
+pub use fallback::*;
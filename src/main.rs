@@ -1,10 +1,17 @@
-use actix_web::{web::Data, App, HttpServer};
-use geticon::handlers::{home, get_favicon_img, get_favicon_json, health_check};
-use geticon::cache::create_default_icon_cache;
+use actix_web::{middleware::from_fn, web::Data, App, HttpServer};
+use geticon::handlers::{home, get_favicon_img, get_favicon_json, batch_lookup, health_check};
+use geticon::admin::{admin_cache_entry, admin_purge, admin_prewarm, AdminToken};
+use geticon::cache::IconCache;
+use geticon::config::Config;
+use geticon::metrics::{metrics_endpoint, track_requests};
+use geticon::explain::explain_discovery;
+use geticon::disk_cache::DiskCache;
+use geticon::redis_cache::RedisBackend;
+use geticon::security::{guard_client_builder, set_private_addresses_allowed};
 use std::env;
 use std::sync::Arc;
 use std::time::Duration;
-use log::{info, debug};
+use log::{info, debug, warn, error};
 use env_logger::Env;
 
 #[actix_web::main]
@@ -14,6 +21,15 @@ async fn main() -> std::io::Result<()> {
     
     info!("Starting GetIcon v{}", env!("CARGO_PKG_VERSION"));
     
+    // Load and validate settings before anything else starts
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err));
+        }
+    };
+    
     // Check if Sentry DSN is provided
     let sentry_enabled = env::var("SENTRY_DSN").is_ok();
     
@@ -38,23 +54,70 @@ async fn main() -> std::io::Result<()> {
         None
     };
 
-    info!("GetIcon server running at http://0.0.0.0:8080");
+    let (bind_address, port) = config.bind_address();
+    info!("GetIcon server running at http://{}:{}", bind_address, port);
+    
+    // The address guard has to be settled before the client is built
+    set_private_addresses_allowed(config.security.allow_private_addresses);
     
     // Create a client with optimized configuration for better performance
-    let client = reqwest::Client::builder()
+    let client_builder = reqwest::Client::builder()
         .danger_accept_invalid_certs(true)
-        .timeout(Duration::from_secs(10))              // Reasonable timeout
-        .pool_max_idle_per_host(10)                    // Keep more connections per host
-        .pool_idle_timeout(Duration::from_secs(30))    // Longer connection reuse
+        .timeout(Duration::from_secs(config.http.timeout_seconds))
+        .pool_max_idle_per_host(config.http.pool_max_idle_per_host)
+        .pool_idle_timeout(Duration::from_secs(config.http.pool_idle_timeout_seconds));
         // rustls-tls feature is already enabled in Cargo.toml
+    
+    // Refuse connections to private, loopback and link-local addresses (SSRF protection)
+    let client = guard_client_builder(client_builder)
         .build()
         .expect("Failed to build reqwest client");
     
     debug!("Created optimized HTTP client with connection pooling");
+    if config.security.allow_private_addresses {
+        warn!("Private address guard is disabled, internal addresses can be fetched");
+    }
+    if let Some(fallback) = &config.fallback.default {
+        info!("Default /img fallback set to '{}'", fallback);
+    }
+    
+    // Create icon cache, shared through Redis when cache.redis.url is set
+    let cache_config = &config.cache;
+    let mut icon_cache = match RedisBackend::from_config(cache_config) {
+        Some(redis) => {
+            if let Err(err) = redis.ping().await {
+                warn!("Redis cache backend is not reachable yet, lookups will miss until it is: {}", err);
+            }
+            IconCache::with_backend(Arc::new(redis), cache_config.max_capacity, cache_config.ttl_seconds)
+                .with_stale_ttl(cache_config.stale_ttl_seconds)
+        },
+        None => IconCache::from_config(cache_config),
+    };
+    
+    // Add the disk tier when cache.disk.dir is set
+    if let Some(disk_cache) = DiskCache::from_config(&cache_config.disk).await {
+        icon_cache = icon_cache.with_disk_cache(disk_cache);
+        icon_cache.warm_from_disk().await;
+    }
+    let icon_cache = Arc::new(icon_cache);
+    debug!("Initialized icon cache with {}s TTL", cache_config.ttl_seconds);
+    
+    // Periodically move entries past their TTL to the expired cache so they can be served stale
+    let sweep_cache = icon_cache.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            sweep_cache.check_and_move_expired_entries().await;
+        }
+    });
+    
+    // Admin routes are only served when admin.token is set
+    let admin_token = AdminToken::from_config(&config.admin).map(Data::new);
     
-    // Create icon cache
-    let icon_cache = Arc::new(create_default_icon_cache());
-    debug!("Initialized icon cache with 2-hour TTL");
+    // Settings are shared with handlers and discovery
+    let bind_address = (bind_address.to_string(), port);
+    let config = Data::new(config);
     
     // Log middleware status
     if sentry_enabled {
@@ -70,13 +133,26 @@ async fn main() -> std::io::Result<()> {
             App::new()
                 .app_data(Data::new(client.clone()))
                 .app_data(Data::new(icon_cache.clone()))
+                .app_data(config.clone())
+                .wrap(from_fn(track_requests))
                 .wrap(sentry_actix::Sentry::new())
                 .service(home)
                 .service(get_favicon_img)
                 .service(get_favicon_json)
+                .service(batch_lookup)
                 .service(health_check)
+                .service(metrics_endpoint)
+                .service(explain_discovery)
+                .configure(|cfg| {
+                    if let Some(token) = &admin_token {
+                        cfg.app_data(token.clone());
+                    }
+                })
+                .service(admin_cache_entry)
+                .service(admin_purge)
+                .service(admin_prewarm)
         })
-        .bind("0.0.0.0:8080")?
+        .bind(bind_address)?
         .run()
         .await
     } else {
@@ -85,12 +161,25 @@ async fn main() -> std::io::Result<()> {
             App::new()
                 .app_data(Data::new(client.clone()))
                 .app_data(Data::new(icon_cache.clone()))
+                .app_data(config.clone())
+                .wrap(from_fn(track_requests))
                 .service(home)
                 .service(get_favicon_img)
                 .service(get_favicon_json)
+                .service(batch_lookup)
                 .service(health_check)
+                .service(metrics_endpoint)
+                .service(explain_discovery)
+                .configure(|cfg| {
+                    if let Some(token) = &admin_token {
+                        cfg.app_data(token.clone());
+                    }
+                })
+                .service(admin_cache_entry)
+                .service(admin_purge)
+                .service(admin_prewarm)
         })
-        .bind("0.0.0.0:8080")?
+        .bind(bind_address)?
         .run()
         .await
     }
@@ -0,0 +1,270 @@
+#[cfg(feature = "server")]
+use actix_web::{body::MessageBody, dev::{ServiceRequest, ServiceResponse}, get, middleware::Next, web, HttpResponse};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+#[cfg(feature = "server")]
+use std::{sync::Arc, time::Instant};
+#[cfg(feature = "server")]
+use crate::cache::IconCache;
+
+/// Upper bounds of the request latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Result of looking up a key in one cache tier
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheLookup {
+    Hit,
+    StaleHit,
+    Miss,
+}
+
+impl CacheLookup {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheLookup::Hit => "hit",
+            CacheLookup::StaleHit => "stale_hit",
+            CacheLookup::Miss => "miss",
+        }
+    }
+}
+
+/// Counters sharing a name, one per combination of label values
+struct CounterFamily {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterFamily {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        CounterFamily { name, help, labels, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    fn inc(&self, label_values: &[&str]) {
+        let key = label_values.iter().map(|v| v.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+        for (label_values, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{} {}", self.name, label_set(self.labels, label_values, None), value);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Latency histograms sharing a name, one per combination of label values
+struct HistogramFamily {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, Histogram>>,
+}
+
+impl HistogramFamily {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        HistogramFamily { name, help, labels, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    fn observe(&self, label_values: &[&str], seconds: f64) {
+        let key = label_values.iter().map(|v| v.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        let histogram = values.entry(key).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+        for (label_values, histogram) in self.values.lock().unwrap().iter() {
+            for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                let le = bound.to_string();
+                let _ = writeln!(out, "{}_bucket{} {}", self.name, label_set(self.labels, label_values, Some(&le)), bucket);
+            }
+            let _ = writeln!(out, "{}_bucket{} {}", self.name, label_set(self.labels, label_values, Some("+Inf")), histogram.count);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, label_set(self.labels, label_values, None), histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, label_set(self.labels, label_values, None), histogram.count);
+        }
+    }
+}
+
+/// Formats `{name="value",...}`, with an optional `le` label for histogram buckets
+fn label_set(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Counters and histograms exported on /metrics in the Prometheus text format
+pub struct Metrics {
+    requests: CounterFamily,
+    request_duration: HistogramFamily,
+    cache_lookups: CounterFamily,
+    upstream_fetches: CounterFamily,
+    icon_sources: CounterFamily,
+    validation_failures: CounterFamily,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            requests: CounterFamily::new(
+                "geticon_http_requests_total",
+                "HTTP requests handled, by endpoint and status",
+                &["endpoint", "status"],
+            ),
+            request_duration: HistogramFamily::new(
+                "geticon_http_request_duration_seconds",
+                "Time spent handling HTTP requests, by endpoint and status",
+                &["endpoint", "status"],
+            ),
+            cache_lookups: CounterFamily::new(
+                "geticon_cache_lookups_total",
+                "Cache lookups by tier and result (hit, stale_hit, miss)",
+                &["tier", "result"],
+            ),
+            upstream_fetches: CounterFamily::new(
+                "geticon_upstream_fetches_total",
+                "Requests to websites for pages, icons, validation, manifests, browserconfig files and theme colors, by outcome",
+                &["target", "outcome"],
+            ),
+            icon_sources: CounterFamily::new(
+                "geticon_chosen_icon_source_total",
+                "Where the icons that were served were found",
+                &["source"],
+            ),
+            validation_failures: CounterFamily::new(
+                "geticon_validation_failures_total",
+                "Candidate icons rejected during validation, by reason",
+                &["reason"],
+            ),
+        }
+    }
+
+    pub fn record_request(&self, endpoint: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        self.requests.inc(&[endpoint, &status]);
+        self.request_duration.observe(&[endpoint, &status], elapsed.as_secs_f64());
+    }
+
+    pub fn record_cache_lookup(&self, tier: &str, result: CacheLookup) {
+        self.cache_lookups.inc(&[tier, result.as_str()]);
+    }
+
+    /// Counts a request to a website; `target` is `page`, `icon`, `validation`, `manifest`,
+    /// `browserconfig` or `theme_color`
+    pub fn record_upstream_fetch(&self, target: &str, outcome: &str) {
+        self.upstream_fetches.inc(&[target, outcome]);
+    }
+
+    pub fn record_icon_source(&self, source: &str) {
+        self.icon_sources.inc(&[source]);
+    }
+
+    pub fn record_validation_failure(&self, reason: &str) {
+        self.validation_failures.inc(&[reason]);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.requests.render(&mut out);
+        self.request_duration.render(&mut out);
+        self.cache_lookups.render(&mut out);
+        self.upstream_fetches.render(&mut out);
+        self.icon_sources.render(&mut out);
+        self.validation_failures.render(&mut out);
+        out
+    }
+}
+
+/// The process-wide metrics, shared by handlers, the cache and discovery
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Middleware counting requests and their latency by route pattern and status
+#[cfg(feature = "server")]
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let started = Instant::now();
+    // Unknown paths share one label, so scanners can't create unbounded series
+    let endpoint = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+    let result = next.call(req).await;
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    metrics().record_request(&endpoint, status.as_u16(), started.elapsed());
+    result
+}
+
+/// Handler for /metrics - exports metrics in the Prometheus text format
+#[cfg(feature = "server")]
+#[get("/metrics")]
+pub async fn metrics_endpoint(cache: web::Data<Arc<IconCache>>) -> HttpResponse {
+    let mut body = metrics().render();
+
+    // Cache sizes are read when scraped rather than tracked on every change
+    // Layers of backends that can't count their entries cheaply (Redis) are left out
+    let mut layers = match cache.stats().await {
+        Some((main_count, expired_count, negative_count)) => vec![("main", main_count), ("expired", expired_count), ("negative", negative_count)],
+        None => Vec::new(),
+    };
+    layers.push(("discovery", cache.discovery_count().await));
+    let _ = writeln!(body, "# HELP geticon_cache_entries Entries currently held by each cache layer");
+    let _ = writeln!(body, "# TYPE geticon_cache_entries gauge");
+    for (layer, count) in layers {
+        let _ = writeln!(body, "geticon_cache_entries{{layer=\"{}\"}} {}", layer, count);
+    }
+    if let Some((entries, bytes)) = cache.disk_stats() {
+        let _ = writeln!(body, "geticon_cache_entries{{layer=\"disk\"}} {}", entries);
+        let _ = writeln!(body, "# HELP geticon_disk_cache_bytes Bytes stored in the disk cache tier");
+        let _ = writeln!(body, "# TYPE geticon_disk_cache_bytes gauge");
+        let _ = writeln!(body, "geticon_disk_cache_bytes {}", bytes);
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(body)
+}
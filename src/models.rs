@@ -1,7 +1,37 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
 use crate::url_utils::normalize_url_string;
 
-#[derive(Serialize, Hash, Eq, PartialEq, Debug, Clone)]
+/// Where a candidate icon was found
+#[derive(Serialize, Deserialize, Hash, Eq, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum IconSource {
+    /// A `<link>` or `<meta>` tag in the page's HTML
+    Html,
+    /// The icons of a web app manifest
+    Manifest,
+    /// A tile image from a browserconfig.xml file
+    Browserconfig,
+    /// The page's Open Graph image
+    OgImage,
+    /// A conventional path such as /favicon.ico, tried without being referenced
+    WellKnown,
+}
+
+impl IconSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IconSource::Html => "html",
+            IconSource::Manifest => "manifest",
+            IconSource::Browserconfig => "browserconfig",
+            IconSource::OgImage => "og_image",
+            IconSource::WellKnown => "well_known",
+        }
+    }
+}
+
+/// Icons are identified by their URL and frame, so the same file found in several places is only listed once
+#[derive(Serialize, Debug, Clone)]
 pub struct Icon {
     pub url: String,
     #[serde(rename = "type")]
@@ -10,10 +40,34 @@ pub struct Icon {
     pub height: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub purpose: Option<String>,
+    /// Index of the embedded image when this icon is one frame of a multi-resolution ICO file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame: Option<u32>,
+    /// Fill color declared on pinned-tab mask icons
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    /// Where the icon was found
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<IconSource>,
     #[serde(skip)]
     pub score: u32,
 }
 
+impl PartialEq for Icon {
+    fn eq(&self, other: &Self) -> bool {
+        self.url == other.url && self.frame == other.frame
+    }
+}
+
+impl Eq for Icon {}
+
+impl Hash for Icon {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.url.hash(state);
+        self.frame.hash(state);
+    }
+}
+
 impl Icon {
     pub fn new(url: String, content_type: String, width: Option<u32>, height: Option<u32>) -> Self {
         // Normalize URL for storage and comparison
@@ -27,6 +81,9 @@ impl Icon {
             width,
             height,
             purpose: None,
+            frame: None,
+            color: None,
+            source: None,
             score: 0,
         }
     }
@@ -36,47 +93,75 @@ impl Icon {
         self
     }
     
+    pub fn with_color(mut self, color: Option<String>) -> Self {
+        self.color = color;
+        self
+    }
+    
+    pub fn with_source(mut self, source: IconSource) -> Self {
+        self.source = Some(source);
+        self
+    }
+    
     pub fn calculate_score(&mut self) {
-        let mut score = 0;
-        
+        self.score = self.score_breakdown().total;
+    }
+    
+    /// Points the icon gets for its format, size and purpose, which add up to its score
+    pub fn score_breakdown(&self) -> ScoreBreakdown {
         // Score based on format quality
-        match self.content_type.as_str() {
-            "image/svg+xml" => score += 50, // SVG is best for scaling
-            "image/png" => score += 40,     // PNG is good quality
-            "image/webp" => score += 35,    // WEBP is good but less supported
-            "image/jpeg" | "image/jpg" => score += 30,
-            "image/x-icon" | "image/vnd.microsoft.icon" => score += 20,
-            "image/gif" => score += 10,
-            _ => score += 5,
-        }
+        let format = match self.content_type.as_str() {
+            "image/svg+xml" => 50, // SVG is best for scaling
+            "image/png" => 40,     // PNG is good quality
+            "image/webp" => 35,    // WEBP is good but less supported
+            "image/jpeg" | "image/jpg" => 30,
+            "image/x-icon" | "image/vnd.microsoft.icon" => 20,
+            "image/gif" => 10,
+            _ => 5,
+        };
         
         // Score based on size (larger is better for high-DPI displays)
-        if let (Some(width), Some(height)) = (self.width, self.height) {
+        let size = if let (Some(width), Some(height)) = (self.width, self.height) {
             let size = width.max(height);
-            if size >= 512 { score += 30; }
-            else if size >= 256 { score += 25; }
-            else if size >= 192 { score += 20; }
-            else if size >= 128 { score += 15; }
-            else if size >= 64 { score += 10; }
-            else if size >= 32 { score += 5; }
-            else { score += 2; }
+            if size >= 512 { 30 }
+            else if size >= 256 { 25 }
+            else if size >= 192 { 20 }
+            else if size >= 128 { 15 }
+            else if size >= 64 { 10 }
+            else if size >= 32 { 5 }
+            else { 2 }
         } else {
             // Unknown size, modest score
-            score += 3;
-        }
+            3
+        };
         
         // Score based on purpose
+        let mut purpose_score = 0;
         if let Some(purpose) = &self.purpose {
-            if purpose.contains("maskable") { score += 10; } // Good for Android adaptive icons
-            if purpose.contains("apple-touch-icon") { score += 15; } // Apple icons are high quality, typically 180x180
-            if purpose.contains("any") { score += 5; }
-            if purpose.contains("og:image") { score -= 25; } // Penalize OG images - they're fallback only
+            if purpose.contains("maskable") { purpose_score += 10; } // Good for Android adaptive icons
+            if purpose.contains("apple-touch-icon") { purpose_score += 15; } // Apple icons are high quality, typically 180x180
+            if purpose.contains("any") { purpose_score += 5; }
+            if purpose.contains("og:image") { purpose_score -= 25; } // Penalize OG images - they're fallback only
         }
         
-        self.score = score;
+        ScoreBreakdown {
+            format,
+            size,
+            purpose: purpose_score,
+            total: (format + size + purpose_score).max(0) as u32,
+        }
     }
 }
 
+/// The parts of an icon's score, as computed by `Icon::calculate_score`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScoreBreakdown {
+    pub format: i32,
+    pub size: i32,
+    pub purpose: i32,
+    pub total: u32,
+}
+
 #[derive(Serialize)]
 pub struct IconResponse {
     pub url: String,
@@ -84,3 +169,44 @@ pub struct IconResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub best_icon: Option<Icon>,
 }
+
+/// Request body for the /batch and /admin/prewarm endpoints
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub size: Option<u32>,
+}
+
+/// Outcome of a single URL in a /batch request
+/// Holds either the same document /json returns, or an error with its HTTP status
+#[derive(Serialize)]
+pub struct BatchResult {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    /// Stable error code, the same one error responses carry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+}
+
+/// Response body for the /batch endpoint, with results in request order
+#[derive(Serialize)]
+pub struct BatchResponse {
+    pub results: Vec<BatchResult>,
+}
+
+/// Request body for POST /admin/purge, naming either an exact site or a domain prefix
+#[derive(Deserialize)]
+pub struct PurgeRequest {
+    /// Site whose entries are removed, e.g. `example.com`
+    #[serde(default)]
+    pub site: Option<String>,
+    /// Removes every site whose domain starts with this, e.g. `example.` or `shop.example.com`
+    #[serde(default)]
+    pub prefix: Option<String>,
+}
@@ -0,0 +1,511 @@
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::time::{timeout, Instant};
+use url::Url;
+use percent_encoding::percent_decode_str;
+use crate::cache::{CacheBackend, CacheEntry, UpstreamValidators, STALE_TTL_SECONDS};
+use crate::config::CacheConfig;
+use crate::failure::{FailureKind, NegativeEntry, NEGATIVE_HISTORY_SECONDS};
+use log::{debug, info, warn};
+
+/// Prefix of every key written by the Redis backend
+pub const REDIS_KEY_PREFIX: &str = "geticon:";
+
+/// How long connecting or running a single command may take, unless configured otherwise
+pub const DEFAULT_COMMAND_TIMEOUT_SECONDS: u64 = 2;
+
+/// Connections open at once, unless configured otherwise; further commands wait for one to free up
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+/// Keys fetched per SCAN call when purging entries
+const SCAN_BATCH: &str = "1000";
+
+/// Connection settings for a Redis server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedisConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub db: u32,
+}
+
+impl RedisConfig {
+    /// Parses a `redis://[[username]:password@]host[:port][/db]` URL
+    pub fn from_url(value: &str) -> Option<Self> {
+        let url = Url::parse(value.trim()).ok()?;
+        if url.scheme() != "redis" {
+            return None;
+        }
+        let db = match url.path().trim_start_matches('/') {
+            "" => 0,
+            db => db.parse().ok()?,
+        };
+        Some(RedisConfig {
+            host: url.host_str()?.trim_matches(|c| c == '[' || c == ']').to_string(),
+            port: url.port().unwrap_or(6379),
+            username: Some(decode(url.username())?).filter(|u| !u.is_empty()),
+            password: match url.password() {
+                Some(password) => Some(decode(password)?),
+                None => None,
+            },
+            db,
+        })
+    }
+}
+
+/// URLs keep the user info percent-encoded; AUTH needs the literal value
+fn decode(value: &str) -> Option<String> {
+    percent_decode_str(value).decode_utf8().ok().map(|value| value.into_owned())
+}
+
+/// A reply from the server; error replies are turned into `io::Error`s
+#[derive(Debug)]
+enum Reply {
+    Status,
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+/// A single connection speaking RESP, the Redis protocol
+struct Connection {
+    stream: BufStream<TcpStream>,
+}
+
+impl Connection {
+    async fn open(config: &RedisConfig) -> io::Result<Self> {
+        let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+        stream.set_nodelay(true)?;
+        let mut connection = Connection { stream: BufStream::new(stream) };
+
+        if let Some(password) = &config.password {
+            match &config.username {
+                Some(username) => connection.command(&[b"AUTH", username.as_bytes(), password.as_bytes()]).await?,
+                None => connection.command(&[b"AUTH", password.as_bytes()]).await?,
+            };
+        }
+        if config.db != 0 {
+            connection.command(&[b"SELECT", config.db.to_string().as_bytes()]).await?;
+        }
+        Ok(connection)
+    }
+
+    async fn command(&mut self, args: &[&[u8]]) -> io::Result<Reply> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg);
+            request.extend_from_slice(b"\r\n");
+        }
+        self.stream.write_all(&request).await?;
+        self.stream.flush().await?;
+        read_reply(&mut self.stream).await
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid Redis reply: {}", message))
+}
+
+async fn read_line<S: AsyncBufRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Redis connection closed"));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Reads one reply, recursing into arrays
+fn read_reply<S: AsyncBufRead + Unpin + Send>(stream: &mut S) -> BoxFuture<'_, io::Result<Reply>> {
+    async move {
+        let line = read_line(stream).await?;
+        let Some(kind) = line.chars().next() else {
+            return Err(protocol_error("empty line"));
+        };
+        let rest = &line[1..];
+        let length = || rest.parse::<i64>().map_err(|_| protocol_error(&line));
+        match kind {
+            '+' => Ok(Reply::Status),
+            '-' => Err(io::Error::other(format!("Redis error: {}", rest))),
+            ':' => Ok(Reply::Integer(length()?)),
+            '$' => {
+                let Ok(len) = usize::try_from(length()?) else {
+                    return Ok(Reply::Bulk(None));
+                };
+                let mut data = vec![0; len + 2];
+                stream.read_exact(&mut data).await?;
+                data.truncate(len);
+                Ok(Reply::Bulk(Some(data)))
+            },
+            '*' => {
+                let count = usize::try_from(length()?).unwrap_or(0);
+                let mut items = Vec::with_capacity(count);
+                for _ in 0..count {
+                    items.push(read_reply(stream).await?);
+                }
+                Ok(Reply::Array(items))
+            },
+            _ => Err(protocol_error(&line)),
+        }
+    }
+    .boxed()
+}
+
+/// Metadata stored in front of the content of every entry
+#[derive(Serialize, Deserialize)]
+struct StoredMeta {
+    content_type: String,
+    etag: String,
+    stored_at: u64, // Milliseconds since the Unix epoch, so every replica agrees on the age
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upstream: Option<UpstreamValidators>,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Layout: metadata length (u32 LE), metadata JSON, content
+fn encode_entry(entry: &CacheEntry) -> Option<Vec<u8>> {
+    let meta = StoredMeta {
+        content_type: entry.content_type.clone(),
+        etag: entry.etag.clone(),
+        stored_at: now_millis().saturating_sub(entry.inserted_at.elapsed().as_millis() as u64),
+        upstream: entry.upstream.clone(),
+    };
+    let meta_json = serde_json::to_vec(&meta).ok()?;
+    let mut data = Vec::with_capacity(4 + meta_json.len() + entry.content.len());
+    data.extend_from_slice(&(meta_json.len() as u32).to_le_bytes());
+    data.extend_from_slice(&meta_json);
+    data.extend_from_slice(&entry.content);
+    Some(data)
+}
+
+/// Returns the entry and its age
+fn decode_entry(data: &[u8]) -> Option<(CacheEntry, Duration)> {
+    let meta_len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let meta: StoredMeta = serde_json::from_slice(data.get(4..4 + meta_len)?).ok()?;
+    let age = Duration::from_millis(now_millis().saturating_sub(meta.stored_at));
+    let entry = CacheEntry {
+        content: Bytes::copy_from_slice(&data[4 + meta_len..]),
+        content_type: meta.content_type,
+        etag: meta.etag,
+        access_count: 1,
+        inserted_at: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+        upstream: meta.upstream,
+    };
+    Some((entry, age))
+}
+
+/// Negative entry as stored in Redis
+#[derive(Serialize, Deserialize)]
+struct StoredNegative {
+    kind: FailureKind,
+    attempts: u32,
+    failed_at: u64, // Milliseconds since the Unix epoch
+    retry_after_ms: u64,
+}
+
+fn encode_negative(entry: &NegativeEntry) -> Option<Vec<u8>> {
+    serde_json::to_vec(&StoredNegative {
+        kind: entry.kind,
+        attempts: entry.attempts,
+        failed_at: now_millis().saturating_sub(entry.failed_at.elapsed().as_millis() as u64),
+        retry_after_ms: entry.retry_after.as_millis() as u64,
+    })
+    .ok()
+}
+
+fn decode_negative(data: &[u8]) -> Option<NegativeEntry> {
+    let stored: StoredNegative = serde_json::from_slice(data).ok()?;
+    let age = Duration::from_millis(now_millis().saturating_sub(stored.failed_at));
+    Some(NegativeEntry {
+        kind: stored.kind,
+        attempts: stored.attempts,
+        failed_at: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+        retry_after: Duration::from_millis(stored.retry_after_ms),
+    })
+}
+
+/// Cache backend storing entries on a Redis (or Redis-protocol compatible) server,
+/// so several replicas share one cache
+/// Entries expire in Redis once they are too old to be served stale; failures are
+/// logged and treated as cache misses, so an unavailable server never fails requests
+pub struct RedisBackend {
+    config: RedisConfig,
+    idle: Mutex<Vec<Connection>>, // Open connections waiting to be reused
+    connections: Semaphore,       // One permit per connection that may be open
+    max_connections: usize,
+    ttl: Duration,
+    stale_ttl: Duration,
+    command_timeout: Duration,
+}
+
+impl RedisBackend {
+    /// Creates a backend for the given server; connections are opened on first use
+    pub fn new(config: RedisConfig, ttl_seconds: u64) -> Self {
+        RedisBackend {
+            config,
+            idle: Mutex::new(Vec::new()),
+            connections: Semaphore::new(DEFAULT_MAX_CONNECTIONS),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            ttl: Duration::from_secs(ttl_seconds),
+            stale_ttl: Duration::from_secs(STALE_TTL_SECONDS),
+            command_timeout: Duration::from_secs(DEFAULT_COMMAND_TIMEOUT_SECONDS),
+        }
+    }
+
+    /// Sets how long entries are kept after their TTL to be served stale
+    pub fn with_stale_ttl(mut self, stale_ttl_seconds: u64) -> Self {
+        self.stale_ttl = Duration::from_secs(stale_ttl_seconds);
+        self
+    }
+
+    /// Sets how many connections may be open at once
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.connections = Semaphore::new(max_connections);
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Sets how long connecting or running a single command may take
+    pub fn with_command_timeout(mut self, command_timeout: Duration) -> Self {
+        self.command_timeout = command_timeout;
+        self
+    }
+
+    /// Creates the backend configured in `cache.redis`, if any, with the cache's lifetimes
+    pub fn from_config(cache: &CacheConfig) -> Option<Self> {
+        let config = cache.redis.server()?;
+        info!("Using Redis cache backend at {}:{} (db {})", config.host, config.port, config.db);
+        Some(RedisBackend::new(config, cache.ttl_seconds)
+            .with_stale_ttl(cache.stale_ttl_seconds)
+            .with_command_timeout(Duration::from_secs(cache.redis.command_timeout_seconds))
+            .with_max_connections(cache.redis.max_connections))
+    }
+
+    /// Checks that the server is reachable
+    pub async fn ping(&self) -> io::Result<()> {
+        self.command(&[b"PING"]).await.map(|_| ())
+    }
+
+    /// Runs a command on an idle connection, or a new one if none is available
+    /// Waits while `max_connections` commands are already running
+    /// A failing idle connection may have been closed by the server, so it is retried once
+    async fn command(&self, args: &[&[u8]]) -> io::Result<Reply> {
+        let _permit = self.connections.acquire().await
+            .map_err(|_| io::Error::other("Redis connection pool closed"))?;
+        let pooled = self.idle.lock().unwrap().pop();
+        let retry = pooled.is_some();
+        let mut connection = match pooled {
+            Some(connection) => connection,
+            None => self.connect().await?,
+        };
+
+        let reply = match timeout(self.command_timeout, connection.command(args)).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(err)) if !retry || err.kind() == io::ErrorKind::Other => return Err(err),
+            Err(_) if !retry => return Err(io::Error::new(io::ErrorKind::TimedOut, "Redis command timed out")),
+            _ => {
+                debug!("Idle Redis connection failed, retrying on a new one");
+                connection = self.connect().await?;
+                timeout(self.command_timeout, connection.command(args))
+                    .await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Redis command timed out"))??
+            }
+        };
+
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_connections {
+            idle.push(connection);
+        }
+        Ok(reply)
+    }
+
+    async fn connect(&self) -> io::Result<Connection> {
+        timeout(self.command_timeout, Connection::open(&self.config))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Redis connection timed out"))?
+    }
+
+    /// Lists the keys matching a pattern with SCAN, which doesn't block the server
+    async fn scan_keys(&self, pattern: &str) -> io::Result<Vec<Vec<u8>>> {
+        let mut cursor = "0".to_string();
+        let mut found = Vec::new();
+        loop {
+            let reply = self
+                .command(&[b"SCAN", cursor.as_bytes(), b"MATCH", pattern.as_bytes(), b"COUNT", SCAN_BATCH.as_bytes()])
+                .await?;
+            let Reply::Array(mut parts) = reply else {
+                return Err(protocol_error("SCAN did not return an array"));
+            };
+            let (Some(Reply::Array(keys)), Some(Reply::Bulk(Some(next)))) = (parts.pop(), parts.pop()) else {
+                return Err(protocol_error("unexpected SCAN reply"));
+            };
+            found.extend(keys.into_iter().filter_map(|key| match key {
+                Reply::Bulk(Some(key)) => Some(key),
+                _ => None,
+            }));
+            cursor = String::from_utf8_lossy(&next).into_owned();
+            if cursor == "0" {
+                return Ok(found);
+            }
+        }
+    }
+
+    async fn purge_keys(&self, matches: &(dyn Fn(&str) -> bool + Sync)) -> io::Result<u64> {
+        let keys: Vec<Vec<u8>> = self
+            .scan_keys(&format!("{}*", REDIS_KEY_PREFIX))
+            .await?
+            .into_iter()
+            .filter(|key| {
+                let key = String::from_utf8_lossy(key);
+                let key = key.strip_prefix(REDIS_KEY_PREFIX).unwrap_or(&key);
+                key.strip_prefix("icon:")
+                    .or_else(|| key.strip_prefix("negative:"))
+                    .is_some_and(matches)
+            })
+            .collect();
+
+        let mut removed = 0;
+        for chunk in keys.chunks(100) {
+            let mut args: Vec<&[u8]> = vec![b"DEL"];
+            args.extend(chunk.iter().map(Vec::as_slice));
+            if let Reply::Integer(count) = self.command(&args).await? {
+                removed += count as u64;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn entry_key(key: &str) -> String {
+        format!("{}icon:{}", REDIS_KEY_PREFIX, key)
+    }
+
+    fn negative_key(key: &str) -> String {
+        format!("{}negative:{}", REDIS_KEY_PREFIX, key)
+    }
+
+    async fn get_entry(&self, key: &str) -> io::Result<Option<(Arc<CacheEntry>, bool)>> {
+        let data = match self.command(&[b"GET", Self::entry_key(key).as_bytes()]).await? {
+            Reply::Bulk(Some(data)) => data,
+            _ => return Ok(None),
+        };
+        let Some((entry, age)) = decode_entry(&data) else {
+            warn!("Ignoring unreadable Redis cache entry for key: {}", key);
+            return Ok(None);
+        };
+        if age >= self.ttl + self.stale_ttl {
+            return Ok(None);
+        }
+        debug!("Redis cache hit for key: {}", key);
+        Ok(Some((Arc::new(entry), age >= self.ttl)))
+    }
+
+    async fn insert_entry(&self, key: &str, entry: &CacheEntry) -> io::Result<()> {
+        // Keep the entry as long as it may be served, fresh or stale
+        let remaining = (self.ttl + self.stale_ttl).saturating_sub(entry.inserted_at.elapsed());
+        if remaining.is_zero() {
+            return Ok(());
+        }
+        let Some(data) = encode_entry(entry) else {
+            warn!("Failed to serialize Redis cache entry for key: {}", key);
+            return Ok(());
+        };
+        let millis = remaining.as_millis().to_string();
+        self.command(&[b"SET", Self::entry_key(key).as_bytes(), &data, b"PX", millis.as_bytes()]).await?;
+        Ok(())
+    }
+}
+
+impl CacheBackend for RedisBackend {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<(Arc<CacheEntry>, bool)>> {
+        async move {
+            self.get_entry(key).await.unwrap_or_else(|err| {
+                warn!("Redis cache lookup failed for key {}: {}", key, err);
+                None
+            })
+        }
+        .boxed()
+    }
+
+    fn insert(&self, key: String, entry: Arc<CacheEntry>) -> BoxFuture<'_, ()> {
+        async move {
+            debug!("Inserting into Redis cache: {}", key);
+            if let Err(err) = self.insert_entry(&key, &entry).await {
+                warn!("Redis cache insert failed for key {}: {}", key, err);
+            }
+        }
+        .boxed()
+    }
+
+    fn insert_negative(&self, key: String, entry: NegativeEntry) -> BoxFuture<'_, ()> {
+        async move {
+            debug!("Inserting negative Redis cache entry: {} ({:?}, attempt {})", key, entry.kind, entry.attempts);
+            let Some(data) = encode_negative(&entry) else {
+                return;
+            };
+            // Same as the memory backend: kept past the retry time to remember the attempt count
+            let seconds = NEGATIVE_HISTORY_SECONDS.to_string();
+            let result = self
+                .command(&[b"SET", Self::negative_key(&key).as_bytes(), &data, b"EX", seconds.as_bytes()])
+                .await;
+            if let Err(err) = result {
+                warn!("Redis negative cache insert failed for key {}: {}", key, err);
+            }
+        }
+        .boxed()
+    }
+
+    fn get_negative<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<NegativeEntry>> {
+        async move {
+            match self.command(&[b"GET", Self::negative_key(key).as_bytes()]).await {
+                Ok(Reply::Bulk(Some(data))) => decode_negative(&data),
+                Ok(_) => None,
+                Err(err) => {
+                    warn!("Redis negative cache lookup failed for key {}: {}", key, err);
+                    None
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn remove_negative<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()> {
+        async move {
+            if let Err(err) = self.command(&[b"DEL", Self::negative_key(key).as_bytes()]).await {
+                warn!("Redis negative cache removal failed for key {}: {}", key, err);
+            }
+        }
+        .boxed()
+    }
+
+    /// Counting the keys of a shared server means walking its whole keyspace, too slow for
+    /// health checks and scrapes, so Redis entries are left out of the stats
+    fn stats(&self) -> BoxFuture<'_, Option<(u64, u64, u64)>> {
+        async { None }.boxed()
+    }
+
+    fn purge<'a>(&'a self, matches: &'a (dyn Fn(&str) -> bool + Sync)) -> BoxFuture<'a, u64> {
+        async move {
+            self.purge_keys(matches).await.unwrap_or_else(|err| {
+                warn!("Failed to purge Redis cache entries: {}", err);
+                0
+            })
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,181 @@
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use url::{Host, Url};
+use log::{info, warn};
+
+/// Maximum number of HTTP redirects followed for a single request
+const MAX_HTTP_REDIRECTS: usize = 10;
+
+/// Error returned when a request targets a private, loopback or otherwise internal address
+#[derive(Debug, Clone)]
+pub struct BlockedAddressError {
+    pub host: String,
+}
+
+impl fmt::Display for BlockedAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Destination address is not allowed: {}", self.host)
+    }
+}
+
+impl Error for BlockedAddressError {}
+
+/// Whether the private address guard is disabled, set once at startup from `security.allow_private_addresses`
+static PRIVATE_ADDRESSES_ALLOWED: AtomicBool = AtomicBool::new(false);
+
+/// Disables (or re-enables) the private address guard for the whole process
+/// Call it before building HTTP clients: `guard_client_builder` checks it only once per client
+pub fn set_private_addresses_allowed(allowed: bool) {
+    if allowed {
+        info!("Private address guard disabled by configuration");
+    }
+    PRIVATE_ADDRESSES_ALLOWED.store(allowed, Ordering::Relaxed);
+}
+
+/// Returns true if fetching private and internal addresses has been explicitly allowed
+pub fn private_addresses_allowed() -> bool {
+    PRIVATE_ADDRESSES_ALLOWED.load(Ordering::Relaxed)
+}
+
+/// Checks if an IP address belongs to a range we refuse to fetch from
+/// Covers loopback, private, link-local, CGNAT, multicast, documentation and reserved ranges
+pub fn is_blocked_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_blocked_ipv4(v4),
+        IpAddr::V6(v6) => is_blocked_ipv6(v6),
+    }
+}
+
+fn is_blocked_ipv4(ip: &Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local() // 169.254.0.0/16, includes cloud metadata endpoints
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0 // 0.0.0.0/8 "this network"
+        || (a == 100 && (64..128).contains(&b)) // 100.64.0.0/10 carrier-grade NAT
+        || (a == 192 && b == 0 && c == 0) // 192.0.0.0/24 IETF protocol assignments
+        || (a == 198 && (b == 18 || b == 19)) // 198.18.0.0/15 benchmarking
+        || a >= 240 // 240.0.0.0/4 reserved
+}
+
+fn is_blocked_ipv6(ip: &Ipv6Addr) -> bool {
+    // IPv4-mapped (::ffff:a.b.c.d) and NAT64 (64:ff9b::a.b.c.d) addresses embed an IPv4 target
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_blocked_ipv4(&v4);
+    }
+    let segments = ip.segments();
+    if segments[0] == 0x64 && segments[1] == 0xff9b && segments[2..6].iter().all(|s| *s == 0) {
+        let [_, _, _, _, _, _, hi, lo] = segments;
+        let embedded = Ipv4Addr::new((hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8);
+        return is_blocked_ipv4(&embedded);
+    }
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || (segments[0] & 0xfe00) == 0xfc00 // fc00::/7 unique local
+        || (segments[0] & 0xffc0) == 0xfe80 // fe80::/10 link-local
+        || (segments[0] & 0xffc0) == 0xfec0 // fec0::/10 deprecated site-local
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8) // 2001:db8::/32 documentation
+}
+
+/// Checks the host of a URL without resolving it
+/// Rejects IP literals in blocked ranges and well-known local hostnames
+pub fn check_url(url: &Url) -> Result<(), BlockedAddressError> {
+    if private_addresses_allowed() {
+        return Ok(());
+    }
+
+    let blocked = match url.host() {
+        Some(Host::Ipv4(ip)) => is_blocked_ipv4(&ip),
+        Some(Host::Ipv6(ip)) => is_blocked_ipv6(&ip),
+        Some(Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            domain == "localhost" || domain.ends_with(".localhost")
+        },
+        None => true,
+    };
+
+    if blocked {
+        warn!("Blocked request to internal address: {}", url);
+        return Err(BlockedAddressError {
+            host: url.host_str().unwrap_or_default().to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Checks the host of a URL, resolving domain names and checking every returned address
+/// Resolution failures are not treated as blocked; the actual fetch will report them
+pub async fn check_url_resolved(url: &Url) -> Result<(), BlockedAddressError> {
+    check_url(url)?;
+    if private_addresses_allowed() {
+        return Ok(());
+    }
+
+    if let Some(Host::Domain(domain)) = url.host() {
+        let port = url.port_or_known_default().unwrap_or(443);
+        if let Ok(addrs) = tokio::net::lookup_host((domain, port)).await {
+            for addr in addrs {
+                if is_blocked_ip(&addr.ip()) {
+                    warn!("Blocked request to {} resolving to internal address {}", url, addr.ip());
+                    return Err(BlockedAddressError { host: domain.to_string() });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// DNS resolver that refuses to hand out private or internal addresses
+/// Installed on the HTTP client so every connection is checked, including
+/// redirect targets, meta refresh hops, manifests and browserconfig files
+pub struct GuardedResolver;
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+
+            if let Some(addr) = addrs.iter().find(|addr| is_blocked_ip(&addr.ip())) {
+                warn!("Refusing to connect to {} (resolved to internal address {})", host, addr.ip());
+                return Err(Box::new(BlockedAddressError { host }) as Box<dyn Error + Send + Sync>);
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Redirect policy that checks every hop against the address guard
+/// IP literals bypass the DNS resolver, so they need to be rejected here
+pub fn redirect_policy() -> Policy {
+    Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_HTTP_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if let Err(err) = check_url(attempt.url()) {
+            attempt.error(err)
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Installs the guarded resolver and redirect policy on a client builder
+/// Leaves the builder untouched when private addresses are allowed
+pub fn guard_client_builder(builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+    if private_addresses_allowed() {
+        return builder;
+    }
+    builder
+        .dns_resolver(std::sync::Arc::new(GuardedResolver))
+        .redirect(redirect_policy())
+}
@@ -0,0 +1,97 @@
+use image::{DynamicImage, RgbaImage};
+use resvg::{tiny_skia, usvg};
+use std::str::FromStr;
+use log::debug;
+
+/// Size used when an SVG has no usable intrinsic size and none was requested
+const DEFAULT_RASTER_SIZE: u32 = 256;
+
+/// Largest size an SVG is rasterized to
+const MAX_RASTER_SIZE: u32 = 1024;
+
+/// Checks if content bytes look like an SVG document
+pub fn is_svg_content(bytes: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    (head.starts_with("<svg") || head.starts_with("<?xml") || head.starts_with("<!--"))
+        && head.contains("<svg")
+}
+
+/// Parses a CSS color such as the `color` attribute of a mask-icon link
+pub fn parse_color(value: &str) -> Option<[u8; 3]> {
+    let color = svgtypes::Color::from_str(value.trim()).ok()?;
+    Some([color.red, color.green, color.blue])
+}
+
+/// Parsing options for untrusted SVGs: `<image>` references are only resolved
+/// from embedded `data:` URIs, never from the local filesystem
+fn render_options() -> usvg::Options<'static> {
+    usvg::Options {
+        resources_dir: None,
+        image_href_resolver: usvg::ImageHrefResolver {
+            resolve_data: usvg::ImageHrefResolver::default_data_resolver(),
+            resolve_string: Box::new(|_, _| None),
+        },
+        ..usvg::Options::default()
+    }
+}
+
+/// Renders an SVG to a raster image with a pure-Rust renderer
+/// With a size, the drawing is fitted into a size x size square and centered;
+/// without one, the SVG's own size is used. When a fill color is given, every
+/// painted pixel takes that color and keeps its coverage as alpha (mask-icon behavior)
+pub fn rasterize_svg(bytes: &[u8], size: Option<u32>, fill: Option<[u8; 3]>) -> Option<DynamicImage> {
+    let tree = match usvg::Tree::from_data(bytes, &render_options()) {
+        Ok(tree) => tree,
+        Err(err) => {
+            debug!("Failed to parse SVG: {}", err);
+            return None;
+        }
+    };
+
+    let svg_size = tree.size();
+    let (width, height) = match size {
+        Some(size) => {
+            let size = size.clamp(1, MAX_RASTER_SIZE);
+            (size, size)
+        },
+        None => {
+            let w = svg_size.width().ceil() as u32;
+            let h = svg_size.height().ceil() as u32;
+            if w == 0 || h == 0 {
+                (DEFAULT_RASTER_SIZE, DEFAULT_RASTER_SIZE)
+            } else {
+                // Scale oversized drawings down, keeping their aspect ratio
+                let scale = (MAX_RASTER_SIZE as f32 / w.max(h) as f32).min(1.0);
+                (((w as f32 * scale).round() as u32).max(1), ((h as f32 * scale).round() as u32).max(1))
+            }
+        }
+    };
+
+    // Fit the drawing into the target area and center it
+    let scale = (width as f32 / svg_size.width()).min(height as f32 / svg_size.height());
+    let dx = (width as f32 - svg_size.width() * scale) / 2.0;
+    let dy = (height as f32 - svg_size.height() * scale) / 2.0;
+    let transform = tiny_skia::Transform::from_scale(scale, scale).post_translate(dx, dy);
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)?;
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia stores premultiplied RGBA, the image crate expects straight alpha
+    let mut data = pixmap.take();
+    for pixel in data.chunks_exact_mut(4) {
+        let alpha = pixel[3];
+        if let Some([r, g, b]) = fill {
+            pixel[0] = r;
+            pixel[1] = g;
+            pixel[2] = b;
+        } else if alpha > 0 && alpha < 255 {
+            for channel in &mut pixel[..3] {
+                *channel = ((*channel as u32 * 255 + alpha as u32 / 2) / alpha as u32).min(255) as u8;
+            }
+        }
+    }
+
+    debug!("Rasterized SVG ({}x{}) to {}x{}", svg_size.width(), svg_size.height(), width, height);
+    RgbaImage::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+}
@@ -0,0 +1,352 @@
+use bytes::Bytes;
+use std::io::Cursor;
+use image::{imageops::FilterType, io::{Limits, Reader}, DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat, ImageResult, Rgba, RgbaImage};
+use crate::models::Icon;
+use crate::ico::icon_payload;
+use crate::svg::{is_svg_content, parse_color, rasterize_svg};
+use log::debug;
+
+/// Largest output size we are willing to resample to
+pub const MAX_RESIZE_DIMENSION: u32 = 1024;
+
+/// Largest frame size the ICO format can store
+const MAX_ICO_DIMENSION: u32 = 256;
+
+/// Largest source image width or height we are willing to decode
+const MAX_DECODE_DIMENSION: u32 = 4096;
+
+/// Most memory the decoder may allocate for a single source image
+const MAX_DECODE_ALLOC: u64 = 64 * 1024 * 1024;
+
+/// Quality used when transcoding to JPEG
+const JPEG_QUALITY: u8 = 90;
+
+/// Image formats the /img endpoint can transcode to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    WebP,
+    Jpeg,
+    Ico,
+}
+
+impl OutputFormat {
+    /// Parses the `format` query parameter
+    pub fn from_param(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "webp" => Some(OutputFormat::WebP),
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
+            "ico" => Some(OutputFormat::Ico),
+            _ => None,
+        }
+    }
+
+    /// Maps a MIME type to an output format
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type.trim().to_ascii_lowercase().as_str() {
+            "image/png" => Some(OutputFormat::Png),
+            "image/webp" => Some(OutputFormat::WebP),
+            "image/jpeg" | "image/jpg" => Some(OutputFormat::Jpeg),
+            "image/x-icon" | "image/vnd.microsoft.icon" => Some(OutputFormat::Ico),
+            _ => None,
+        }
+    }
+
+    /// Detects the format of encoded image bytes
+    fn detect(bytes: &[u8]) -> Option<Self> {
+        match image::guess_format(bytes).ok()? {
+            ImageFormat::Png => Some(OutputFormat::Png),
+            ImageFormat::WebP => Some(OutputFormat::WebP),
+            ImageFormat::Jpeg => Some(OutputFormat::Jpeg),
+            ImageFormat::Ico => Some(OutputFormat::Ico),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Ico => "ico",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Ico => "image/x-icon",
+        }
+    }
+}
+
+/// Parses an Accept header into the output formats it explicitly asks for, most preferred first
+/// Returns an empty list when the header accepts any image (`image/*` or `*/*`),
+/// since the original format is then acceptable and no transcoding is needed
+pub fn parse_accept_formats(accept: &str) -> Vec<OutputFormat> {
+    let mut entries: Vec<(OutputFormat, f32)> = Vec::new();
+    for part in accept.split(',') {
+        let mut pieces = part.split(';');
+        let media_type = pieces.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = pieces
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if quality <= 0.0 {
+            continue;
+        }
+        if media_type == "image/*" || media_type == "*/*" {
+            return Vec::new();
+        }
+        if let Some(format) = OutputFormat::from_content_type(&media_type) {
+            if !entries.iter().any(|(f, _)| *f == format) {
+                entries.push((format, quality));
+            }
+        }
+    }
+
+    // Stable sort keeps header order for equal quality values
+    entries.sort_by(|a, b| b.1.total_cmp(&a.1));
+    entries.into_iter().map(|(format, _)| format).collect()
+}
+
+/// Output options requested by the client for the /img endpoint
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageOptions {
+    pub size: Option<u32>,
+    pub resize: bool,
+    /// Format explicitly requested with the `format` query parameter
+    pub format: Option<OutputFormat>,
+    /// Formats explicitly accepted by the client's Accept header, most preferred first
+    pub accepted: Vec<OutputFormat>,
+}
+
+impl ImageOptions {
+    /// Creates options from the size and resize query parameters
+    pub fn new(size: Option<u32>, resize: bool) -> Self {
+        ImageOptions { size, resize, ..Default::default() }
+    }
+
+    /// Sets the explicitly requested output format
+    pub fn with_format(mut self, format: Option<OutputFormat>) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Uses the Accept header to pick a format when none was requested explicitly
+    pub fn with_accept(mut self, accept: Option<&str>) -> Self {
+        self.accepted = accept.map(parse_accept_formats).unwrap_or_default();
+        self
+    }
+
+    /// Returns the size to resample to, if resizing applies to this request
+    pub fn resize_target(&self) -> Option<u32> {
+        if self.resize {
+            self.size
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the response depends on the request's Accept header
+    pub fn varies_by_accept(&self) -> bool {
+        self.format.is_none()
+    }
+
+    /// Picks the format to transcode to for an image currently in `source` format
+    /// Returns None when the original bytes can be served unchanged
+    pub fn target_format(&self, source: Option<OutputFormat>) -> Option<OutputFormat> {
+        if let Some(format) = self.format {
+            return Some(format);
+        }
+        match source {
+            Some(source) if self.accepted.contains(&source) => None,
+            _ => self.accepted.first().copied(),
+        }
+    }
+
+    /// Builds the cache key for a normalized URL, so every output variant is cached separately
+    pub fn cache_key(&self, normalized_url: &str) -> String {
+        let mut key = normalized_url.to_string();
+        if let Some(size) = self.size {
+            key.push_str(&format!(":{}", size));
+        }
+        if self.resize_target().is_some() {
+            key.push_str(":resize");
+        }
+        if let Some(format) = self.format {
+            key.push_str(&format!(":{}", format.as_str()));
+        } else if !self.accepted.is_empty() {
+            let accepted: Vec<&str> = self.accepted.iter().map(|f| f.as_str()).collect();
+            key.push_str(&format!(":accept={}", accepted.join("+")));
+        }
+        key
+    }
+}
+
+/// Parses a boolean query flag such as `resize=true` or `resize=1`
+pub fn parse_flag(value: Option<&String>) -> bool {
+    value.is_some_and(|v| matches!(v.to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+}
+
+/// Applies the requested output options to fetched icon bytes
+/// Returns the bytes and content type to serve
+pub fn transform_icon(icon: &Icon, bytes: Bytes, options: &ImageOptions) -> (Bytes, String) {
+    // Multi-resolution ICO frames are served as PNG
+    let (bytes, content_type) = icon_payload(icon, bytes);
+
+    // SVG is rasterized only when a raster format or exact size was asked for
+    if content_type == "image/svg+xml" || is_svg_content(&bytes) {
+        return transform_svg(icon, bytes, content_type, options);
+    }
+
+    let source = OutputFormat::detect(&bytes).or_else(|| OutputFormat::from_content_type(&content_type));
+    let resize_target = options.resize_target();
+    let target = options.target_format(source);
+
+    if resize_target.is_none() && (target.is_none() || target == source) {
+        return (bytes, content_type);
+    }
+
+    let image = match decode_image(&bytes) {
+        Ok(image) => image,
+        Err(err) => {
+            debug!("Failed to decode {} for transformation, serving original: {:?}", icon.url, err);
+            return (bytes, content_type);
+        }
+    };
+
+    let image = match resize_target {
+        Some(size) => resize_image_to_square(&image, size),
+        None => image,
+    };
+
+    // Resized images default to PNG unless another format was requested
+    let format = target.unwrap_or(OutputFormat::Png);
+    match encode_image(&image, format) {
+        Some(encoded) => (encoded, format.content_type().to_string()),
+        None => {
+            debug!("Failed to encode {} as {}, serving original", icon.url, format.as_str());
+            (bytes, content_type)
+        }
+    }
+}
+
+/// Runs `transform_icon` on the blocking thread pool so decoding and resampling don't stall the async workers
+/// Serves the original bytes if the transformation panics
+pub async fn spawn_transform(icon: &Icon, bytes: Bytes, options: &ImageOptions) -> (Bytes, String) {
+    let (task_icon, task_bytes, task_options) = (icon.clone(), bytes.clone(), options.clone());
+    match tokio::task::spawn_blocking(move || transform_icon(&task_icon, task_bytes, &task_options)).await {
+        Ok(result) => result,
+        Err(err) => {
+            debug!("Transformation of {} failed, serving original: {:?}", icon.url, err);
+            (bytes, icon.content_type.clone())
+        }
+    }
+}
+
+/// Decodes untrusted image bytes, refusing images too large to decode safely
+pub(crate) fn decode_image(bytes: &[u8]) -> ImageResult<DynamicImage> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DECODE_DIMENSION);
+    limits.max_image_height = Some(MAX_DECODE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+
+    let mut reader = Reader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits);
+    reader.decode()
+}
+
+/// Rasterizes an SVG icon when the requested output needs a raster image
+/// Mask icons are filled with their declared color
+fn transform_svg(icon: &Icon, bytes: Bytes, content_type: String, options: &ImageOptions) -> (Bytes, String) {
+    let target = options.target_format(None);
+    if target.is_none() && options.resize_target().is_none() {
+        return (bytes, content_type);
+    }
+
+    let fill = icon.color.as_deref().and_then(parse_color);
+    let image = match rasterize_svg(&bytes, options.size, fill) {
+        Some(image) => image,
+        None => {
+            debug!("Failed to rasterize SVG {}, serving original", icon.url);
+            return (bytes, content_type);
+        }
+    };
+
+    let format = target.unwrap_or(OutputFormat::Png);
+    match encode_image(&image, format) {
+        Some(encoded) => (encoded, format.content_type().to_string()),
+        None => (bytes, content_type),
+    }
+}
+
+/// Decodes a raster image and resamples it to an exact square PNG
+/// Non-square images keep their aspect ratio and are centered on a transparent canvas
+pub fn resize_to_square(bytes: &[u8], size: u32) -> Option<Bytes> {
+    let image = match decode_image(bytes) {
+        Ok(image) => image,
+        Err(err) => {
+            debug!("Failed to decode image for resizing: {:?}", err);
+            return None;
+        }
+    };
+    encode_image(&resize_image_to_square(&image, size), OutputFormat::Png)
+}
+
+/// Resamples a decoded image to an exact square with a high-quality filter
+fn resize_image_to_square(image: &DynamicImage, size: u32) -> DynamicImage {
+    let size = size.clamp(1, MAX_RESIZE_DIMENSION);
+    let (width, height) = image.dimensions();
+    debug!("Resizing {}x{} image to {}x{}", width, height, size, size);
+
+    if width == height {
+        return image.resize_exact(size, size, FilterType::Lanczos3);
+    }
+
+    let fitted = image.resize(size, size, FilterType::Lanczos3);
+    let mut canvas = RgbaImage::new(size, size);
+    let x = (size - fitted.width()) / 2;
+    let y = (size - fitted.height()) / 2;
+    image::imageops::overlay(&mut canvas, &fitted.to_rgba8(), x as i64, y as i64);
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Encodes a decoded image in the given output format
+pub fn encode_image(image: &DynamicImage, format: OutputFormat) -> Option<Bytes> {
+    let mut buffer = Cursor::new(Vec::new());
+    match format {
+        OutputFormat::Png => {
+            image.write_to(&mut buffer, ImageOutputFormat::Png).ok()?;
+        },
+        OutputFormat::Jpeg => {
+            // JPEG has no alpha channel, so flatten transparent pixels onto white
+            let rgba = image.to_rgba8();
+            let mut flattened = RgbaImage::from_pixel(rgba.width(), rgba.height(), Rgba([255, 255, 255, 255]));
+            image::imageops::overlay(&mut flattened, &rgba, 0, 0);
+            DynamicImage::ImageRgba8(flattened)
+                .to_rgb8()
+                .write_to(&mut buffer, ImageOutputFormat::Jpeg(JPEG_QUALITY))
+                .ok()?;
+        },
+        OutputFormat::WebP => {
+            let rgba = image.to_rgba8();
+            image_webp::WebPEncoder::new(&mut buffer)
+                .encode(rgba.as_raw(), rgba.width(), rgba.height(), image_webp::ColorType::Rgba8)
+                .ok()?;
+        },
+        OutputFormat::Ico => {
+            // ICO frames are limited to 256 pixels per side
+            let image = if image.width() > MAX_ICO_DIMENSION || image.height() > MAX_ICO_DIMENSION {
+                image.resize(MAX_ICO_DIMENSION, MAX_ICO_DIMENSION, FilterType::Lanczos3)
+            } else {
+                image.clone()
+            };
+            image.write_to(&mut buffer, ImageOutputFormat::Ico).ok()?;
+        },
+    }
+    Some(Bytes::from(buffer.into_inner()))
+}
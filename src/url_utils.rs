@@ -8,7 +8,7 @@ pub fn normalize_url_string(url: &str) -> Option<String> {
     if url.contains(':') && !url.starts_with("http") {
         if let Some(port_str) = url.split(':').nth(1) {
             if let Some(port) = port_str.split('/').next() {
-                if port.chars().all(|c| c.is_digit(10)) {
+                if port.chars().all(|c| c.is_ascii_digit()) {
                     // Try parsing with the port
                     let with_port = format!("https://{}:{}", url.split(':').next().unwrap_or(url), port);
                     if let Ok(parsed) = Url::parse(&with_port) {
@@ -2,10 +2,20 @@ use bytes::Bytes;
 use reqwest;
 use std::collections::HashMap;
 use crate::models::Icon;
-use std::time::Duration;
 use log::{info, debug};
 use scraper::{Html, Selector};
 use futures::{stream, StreamExt};
+use crate::dimensions::sniff_dimensions;
+use crate::ico::{parse_ico_directory, expand_ico_frames};
+use crate::metrics::metrics;
+use crate::explain::DiscoveryTrace;
+use crate::error::GetIconError;
+
+/// Maximum number of bytes downloaded when sniffing icon dimensions
+const DIMENSION_PROBE_BYTES: usize = 65536;
+
+/// Largest icon accepted from an origin (10 MB)
+pub const MAX_ICON_BYTES: u64 = 10 * 1024 * 1024;
 
 /// Checks if a content type header indicates an image
 pub fn is_image_content_type(content_type: &str) -> bool {
@@ -33,171 +43,214 @@ pub fn has_valid_image_signature(bytes: &[u8]) -> bool {
     bytes.starts_with(b"\x00\x00\x01\x00") // ICO
 }
 
-/// Validates an icon by making a HEAD request to check if it exists and has content
+/// Validates an icon with a ranged GET, checking that it exists, is an image and isn't too large
+/// Returns the first DIMENSION_PROBE_BYTES bytes, enough to read image headers and the ICO directory,
+/// or why the icon was rejected
 pub async fn validate_icon(
     client: &reqwest::Client, 
     icon: &Icon, 
     forwarded_headers: Option<&HashMap<String, String>>
-) -> bool {
+) -> Result<Vec<u8>, GetIconError> {
     debug!("Validating icon: {}", icon.url);
     
-    // Create a copy of forwarded headers that we can modify
-    let mut headers = match forwarded_headers {
-        Some(h) => h.clone(),
-        None => HashMap::new(),
+    // Reject icons pointing at internal addresses before making any request
+    let icon_url = match url::Url::parse(&icon.url) {
+        Ok(icon_url) => icon_url,
+        Err(_) => {
+            debug!("Icon validation failed - Invalid URL: {}", icon.url);
+            return Err(rejected(GetIconError::InvalidUrl));
+        }
     };
+    if let Err(err) = crate::security::check_url(&icon_url) {
+        debug!("Icon validation failed - Blocked URL: {}", icon.url);
+        return Err(rejected(err.into()));
+    }
     
-    // Override the User-Agent with our selected one based on icon type
-    let user_agent = crate::favicon::select_user_agent_for_icon(icon);
-    headers.insert("User-Agent".to_string(), user_agent.to_string());
-    
-    let mut request_builder = client.head(&icon.url)
-        .timeout(Duration::from_secs(5));
+    // No per-request timeout, so validations honor http.timeout_seconds like every other fetch
+    let mut request_builder = client.get(&icon.url);
     
     // Apply headers
-    for (name, value) in &headers {
-        request_builder = request_builder.header(name, value);
+    if let Some(headers) = forwarded_headers {
+        for (name, value) in headers {
+            request_builder = request_builder.header(name, value);
+        }
     }
     
-    match request_builder.send().await {
-        Ok(response) => {
-            let status = response.status();
-            if !status.is_success() {
-                debug!("Icon validation failed - HTTP status: {} for URL: {}", status, icon.url);
-                return false;
-            }
-            
-            // Check if the response was redirected to a different URL
-            let final_url = response.url().to_string();
-            if final_url != icon.url {
-                debug!("Icon was redirected: {} -> {}", icon.url, final_url);
-                
-                // The request was redirected, check if the final URL is still an image
-                if let Some(content_type) = response.headers().get("content-type") {
-                    if let Ok(content_type_str) = content_type.to_str() {
-                        // If redirected to a non-image resource (like HTML), reject it
-                        if !is_image_content_type(content_type_str) {
-                            debug!("Icon validation failed - Redirected to non-image content type: {}", content_type_str);
-                            return false;
-                        }
-                    }
-                }
-                
-                // For redirects, do a small GET request to peek at the content
-                // This helps detect cookie consent pages and other non-image content
-                debug!("Peeking at content for redirected URL: {}", final_url);
-                if !peek_content_is_valid_image(client, &final_url, &headers).await {
-                    debug!("Icon validation failed - Peeked content is not a valid image");
-                    return false;
-                }
-            }
-            
-            // Check content type header to ensure it's an image
-            if let Some(content_type) = response.headers().get("content-type") {
-                if let Ok(content_type_str) = content_type.to_str() {
-                    if !is_image_content_type(content_type_str) {
-                        debug!("Icon validation failed - Non-image content type: {}", content_type_str);
-                        return false;
-                    }
-                    debug!("Icon content type: {}", content_type_str);
-                }
-            }
-            
-            // Check content length if available
-            if let Some(length) = response.headers().get("content-length") {
-                if let Ok(size) = length.to_str().unwrap_or("0").parse::<u64>() {
-                    if size == 0 {
-                        debug!("Icon validation failed - Zero content length");
-                        return false;
-                    }
-                    debug!("Icon content length: {} bytes", size);
-                }
-            }
-            
-            // If no content-length header, assume it's valid if we've passed other checks
-            debug!("Icon validation successful: {}", icon.url);
-            true
-        },
+    // Override the User-Agent with our selected one based on icon type
+    request_builder = request_builder
+        .header("User-Agent", crate::favicon::select_user_agent_for_icon(icon))
+        .header("Range", format!("bytes=0-{}", DIMENSION_PROBE_BYTES - 1));
+    
+    let result = request_builder.send().await;
+    crate::favicon::record_fetch("validation", &result);
+    let mut response = match result {
+        Ok(response) => response,
         Err(err) => {
             debug!("Icon validation failed - Request error: {} for URL: {}", err, icon.url);
-            false
+            return Err(rejected(GetIconError::from_request_error(&err)));
+        }
+    };
+    
+    let status = response.status();
+    if status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        // Even the first byte is out of range, so the file is empty
+        debug!("Icon validation failed - Empty file at URL: {}", icon.url);
+        return Err(rejected(GetIconError::Empty));
+    }
+    if !status.is_success() {
+        debug!("Icon validation failed - HTTP status: {} for URL: {}", status, icon.url);
+        return Err(rejected(GetIconError::HttpStatus(status.as_u16())));
+    }
+    
+    // Check content type header to ensure it's an image
+    let content_type = response.headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    if let Some(content_type) = &content_type {
+        if !is_image_content_type(content_type) {
+            debug!("Icon validation failed - Non-image content type: {}", content_type);
+            return Err(rejected(GetIconError::NotAnImage(content_type.clone())));
         }
+        debug!("Icon content type: {}", content_type);
     }
+    
+    // Check the full size, from Content-Range for partial responses or Content-Length otherwise
+    if let Some(size) = full_content_length(&response) {
+        if size == 0 {
+            debug!("Icon validation failed - Zero content length");
+            return Err(rejected(GetIconError::Empty));
+        }
+        if size > MAX_ICON_BYTES {
+            debug!("Icon validation failed - Content length {} over the limit", size);
+            return Err(rejected(GetIconError::TooLarge(size)));
+        }
+        debug!("Icon content length: {} bytes", size);
+    }
+    
+    // Servers that ignore the Range header send the full body, so stop reading once we have enough
+    let final_url = response.url().to_string();
+    let mut prefix = Vec::new();
+    while prefix.len() < DIMENSION_PROBE_BYTES {
+        match response.chunk().await {
+            Ok(Some(chunk)) => prefix.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(err) => {
+                debug!("Icon validation failed - Error reading body: {} for URL: {}", err, icon.url);
+                return Err(rejected(GetIconError::from_request_error(&err)));
+            }
+        }
+    }
+    if prefix.is_empty() {
+        debug!("Icon validation failed - Empty body");
+        return Err(rejected(GetIconError::Empty));
+    }
+    
+    // Redirects can end up at cookie consent pages and other non-image content
+    if final_url != icon.url {
+        debug!("Icon was redirected: {} -> {}", icon.url, final_url);
+        if is_html_content(&prefix) || !has_valid_image_signature(&prefix) {
+            debug!("Icon validation failed - Redirected content is not a valid image");
+            return Err(rejected(GetIconError::NotAnImage(format!("redirected to {}", final_url))));
+        }
+    }
+    
+    debug!("Icon validation successful: {}", icon.url);
+    Ok(prefix)
+}
+
+/// Full size of a response body: the total from Content-Range, or Content-Length
+fn full_content_length(response: &reqwest::Response) -> Option<u64> {
+    let header = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok());
+    if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        // Content-Range: bytes 0-65535/1234567 (the total may be *)
+        return header("content-range")
+            .and_then(|range| range.rsplit('/').next())
+            .and_then(|total| total.parse().ok());
+    }
+    header("content-length").and_then(|length| length.parse().ok())
 }
 
-/// Helper function to peek at content and validate it's an image
-async fn peek_content_is_valid_image(
+/// Counts a validation failure and passes it on
+fn rejected(err: GetIconError) -> GetIconError {
+    metrics().record_validation_failure(err.code());
+    err
+}
+
+/// Validates an icon and replaces its declared dimensions with the real ones
+/// Multi-resolution ICO files are expanded into one icon per embedded frame
+/// Returns the icons with recalculated scores, or an empty list if validation failed
+/// Records the result in `trace`, if given
+pub async fn validate_and_measure_icon(
     client: &reqwest::Client,
-    url: &str,
-    headers: &HashMap<String, String>
-) -> bool {
-    debug!("Peeking at content for URL: {}", url);
-    
-    let mut peek_request = client.get(url)
-        .timeout(Duration::from_secs(5));
+    icon: Icon,
+    forwarded_headers: Option<&HashMap<String, String>>,
+    trace: Option<&DiscoveryTrace>
+) -> Vec<Icon> {
+    let prefix = match validate_icon(client, &icon, forwarded_headers).await {
+        Ok(prefix) => prefix,
+        Err(err) => {
+            if let Some(trace) = trace {
+                trace.rejected(&icon, &err);
+            }
+            return Vec::new();
+        }
+    };
     
-    // Apply headers
-    for (name, value) in headers {
-        peek_request = peek_request.header(name, value);
+    let measured = measure_icon(icon.clone(), &prefix);
+    if let Some(trace) = trace {
+        trace.validated(&icon, &measured);
+    }
+    measured
+}
+
+/// Replaces the declared dimensions of a valid icon with the real ones read from its first bytes,
+/// expanding ICO frames
+fn measure_icon(mut icon: Icon, prefix: &[u8]) -> Vec<Icon> {
+    // ICO frames were already measured from the icon directory when they were expanded
+    if icon.frame.is_some() {
+        return vec![icon];
     }
     
-    // Set range header to only get the first 512 bytes
-    peek_request = peek_request.header("Range", "bytes=0-511");
+    // List every frame of a multi-resolution ICO as its own candidate
+    if let Some(frames) = parse_ico_directory(prefix).filter(|frames| frames.len() > 1) {
+        debug!("Icon {} is an ICO with {} frames", icon.url, frames.len());
+        return expand_ico_frames(&icon, &frames);
+    }
     
-    match peek_request.send().await {
-        Ok(peek_response) => {
-            let status = peek_response.status();
-            debug!("Peek response status: {} for URL: {}", status, url);
-            
-            if let Ok(bytes) = peek_response.bytes().await {
-                if bytes.is_empty() {
-                    debug!("Peek content is empty for URL: {}", url);
-                    return false;
-                }
-                
-                // Check for HTML content
-                if is_html_content(&bytes) {
-                    debug!("Peek content is HTML, not an image for URL: {}", url);
-                    return false;
-                }
-                
-                // Check for common image signatures
-                let is_valid = has_valid_image_signature(&bytes);
-                if is_valid {
-                    debug!("Peek content has valid image signature for URL: {}", url);
-                } else {
-                    debug!("Peek content does not have valid image signature for URL: {}", url);
-                }
-                return is_valid;
-            } else {
-                debug!("Failed to read peek content bytes for URL: {}", url);
-            }
-            false
-        },
-        Err(err) => {
-            debug!("Peek request failed: {} for URL: {}", err, url);
-            false
+    if let Some((width, height)) = sniff_dimensions(prefix) {
+        if icon.width != Some(width) || icon.height != Some(height) {
+            debug!("Icon {} declared {}x{} but is actually {}x{}", 
+                icon.url,
+                icon.width.unwrap_or(0),
+                icon.height.unwrap_or(0),
+                width,
+                height);
         }
+        icon.width = Some(width);
+        icon.height = Some(height);
+        icon.calculate_score();
     }
+    
+    vec![icon]
 }
 
 /// Validates a list of icons by checking if they exist and are valid images
-/// Returns a list of validated icons
-/// Now uses parallel processing for significant performance improvement
+/// Returns a list of validated icons with their measured dimensions, sorted by score
+/// Now uses parallel processing, validating up to `max_concurrent` icons at a time
+/// Each result is recorded in `trace`, if given
 pub async fn validate_icons(
     client: &reqwest::Client,
     icons: &[Icon],
-    forwarded_headers: &HashMap<String, String>
+    forwarded_headers: &HashMap<String, String>,
+    max_concurrent: usize,
+    trace: Option<&DiscoveryTrace>
 ) -> Vec<Icon> {
     debug!("Validating {} icons in parallel", icons.len());
     
     // Clone icons for concurrent validation
     let icons_to_validate: Vec<_> = icons.to_vec();
     
-    // Define the maximum number of concurrent validation tasks
-    // This controls how many simultaneous HTTP requests we make
-    const MAX_CONCURRENT_VALIDATIONS: usize = 5;
-    
     // Validate icons in parallel using stream
     let validated_icons: Vec<Icon> = stream::iter(icons_to_validate)
         .map(|icon| {
@@ -211,24 +264,28 @@ pub async fn validate_icons(
                     icon.width.unwrap_or(0),
                     icon.height.unwrap_or(0));
                 
-                let is_valid = validate_icon(&client, &icon, Some(&headers)).await;
+                let icon_url = icon.url.clone();
+                let validated = validate_and_measure_icon(&client, icon, Some(&headers), trace).await;
                 
-                if is_valid {
-                    debug!("Icon validated successfully: {}", icon.url);
-                    Some(icon)
+                if validated.is_empty() {
+                    debug!("Icon validation failed: {}", icon_url);
                 } else {
-                    debug!("Icon validation failed: {}", icon.url);
-                    None
+                    debug!("Icon validated successfully: {}", icon_url);
                 }
+                validated
             }
         })
-        .buffer_unordered(MAX_CONCURRENT_VALIDATIONS) // Process up to MAX_CONCURRENT_VALIDATIONS at a time
+        .buffer_unordered(max_concurrent.max(1)) // This controls how many simultaneous HTTP requests we make
         .collect::<Vec<_>>()
         .await
         .into_iter()
-        .filter_map(|x| x) // Keep only the valid icons (filter_map on the result, not the future)
+        .flatten() // Keep only the valid icons, including expanded ICO frames
         .collect();
     
+    // Measured dimensions can change scores, and parallel validation loses the original order
+    let mut validated_icons = validated_icons;
+    validated_icons.sort_by_key(|icon| std::cmp::Reverse(icon.score));
+    
     info!("Validated {}/{} icons successfully", validated_icons.len(), icons.len());
     validated_icons
 }
@@ -255,6 +312,28 @@ pub fn extract_meta_refresh_url(html_content: &[u8]) -> Option<String> {
     None
 }
 
+/// Extracts the color declared by a `<meta name="theme-color">` tag if present
+/// Media-specific variants are ignored in favor of the first unconditional one
+pub fn extract_theme_color(html_content: &[u8]) -> Option<String> {
+    let html_str = String::from_utf8_lossy(html_content);
+    let document = Html::parse_document(&html_str);
+    let selector = Selector::parse("meta[name='theme-color']").unwrap();
+
+    let mut media_specific = None;
+    for element in document.select(&selector) {
+        let content = match element.value().attr("content") {
+            Some(content) if !content.trim().is_empty() => content.trim().to_string(),
+            _ => continue,
+        };
+        if element.value().attr("media").is_none() {
+            return Some(content);
+        }
+        media_specific.get_or_insert(content);
+    }
+
+    media_specific
+}
+
 /// Validates image content by checking file signatures and using the image crate
 pub fn validate_image_content(bytes: &Bytes, content_type: &str) -> bool {
     debug!("Validating image content of type: {}, size: {} bytes", content_type, bytes.len());